@@ -0,0 +1,95 @@
+/*
+    Copyright (C) 2018 Oskari Timperi <oskari.timperi@iki.fi>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Minimal reading of track points from GPX files.
+//!
+//! Only the `lat` and `lon` attributes and the `time` element of `trkpt`
+//! elements are read. Points without a time are ignored.
+
+use chrono::DateTime;
+
+/// A point of a GPX track.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackPoint {
+    /// Seconds since the Unix epoch.
+    pub time: i64,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+fn get_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let prefix = format!("{}=", name);
+    let value = tag.split_whitespace().find_map(|attribute| attribute.strip_prefix(prefix.as_str()))?;
+    let quote = value.chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let value = &value[1..];
+    value.find(quote).map(|end| &value[..end])
+}
+
+fn parse_point(element: &str) -> Result<Option<TrackPoint>, String> {
+    let tag = &element[..element.find('>').unwrap_or(element.len())];
+
+    let coordinate = |name: &str| -> Result<f64, String> {
+        get_attribute(tag, name)
+            .ok_or_else(|| format!("track point without {}", name))?
+            .trim()
+            .parse::<f64>()
+            .map_err(|error| format!("invalid track point {}: {}", name, error))
+    };
+
+    let latitude = coordinate("lat")?;
+    let longitude = coordinate("lon")?;
+
+    let time = match element.find("<time>") {
+        Some(start) => {
+            let rest = &element[start + "<time>".len()..];
+            let end = rest.find("</time>").ok_or("unterminated time element")?;
+            rest[..end].trim()
+        },
+        None => return Ok(None),
+    };
+
+    let time = DateTime::parse_from_rfc3339(time)
+        .map_err(|error| format!("invalid track point time {}: {}", time, error))?;
+
+    Ok(Some(TrackPoint { time: time.timestamp(), latitude, longitude }))
+}
+
+/// Returns the timed track points of a GPX document, sorted by time.
+pub fn parse_track_points(data: &str) -> Result<Vec<TrackPoint>, String> {
+    let mut points = Vec::new();
+    let mut rest = data;
+
+    while let Some(start) = rest.find("<trkpt") {
+        rest = &rest[start..];
+        let tag_end = rest.find('>').ok_or("unterminated trkpt element")?;
+        let end = if rest[..tag_end].ends_with('/') {
+            tag_end + 1
+        } else {
+            rest.find("</trkpt>").ok_or("unterminated trkpt element")? + "</trkpt>".len()
+        };
+
+        if let Some(point) = parse_point(&rest[..end])? {
+            points.push(point);
+        }
+
+        rest = &rest[end..];
+    }
+
+    points.sort_by_key(|point| point.time);
+
+    Ok(points)
+}
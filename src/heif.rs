@@ -0,0 +1,308 @@
+/*
+    Copyright (C) 2018 Oskari Timperi <oskari.timperi@iki.fi>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Extraction of the Exif block from HEIF (ISO/IEC 23008-12) images.
+//!
+//! HEIF files are ISO base media files. The Exif data is stored as an item of
+//! type `Exif` whose location in the file is described by the `iloc` box in
+//! the top-level `meta` box.
+
+use exif;
+
+const TRUNCATED: exif::Error = exif::Error::InvalidFormat("truncated HEIF box");
+
+/// Returns true if the data starts with an `ftyp` box of a HEIF brand.
+pub fn is_heif(data: &[u8]) -> bool {
+    if data.len() < 12 || &data[4..8] != b"ftyp" {
+        return false;
+    }
+    matches!(&data[8..12], b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1" | b"avif")
+}
+
+/// Returns the TIFF data of the Exif item in a HEIF file.
+pub fn extract_exif(data: &[u8]) -> Result<&[u8], exif::Error> {
+    let meta = find_box(data, b"meta")?.ok_or(exif::Error::NotFound("HEIF meta box not found"))?;
+    // meta is a full box, skip version and flags.
+    let meta = meta.get(4..).ok_or(TRUNCATED)?;
+
+    let iinf = find_box(meta, b"iinf")?.ok_or(exif::Error::NotFound("HEIF iinf box not found"))?;
+    let item_id = find_exif_item(iinf)?.ok_or(exif::Error::NotFound("Exif item not found"))?;
+
+    let iloc = find_box(meta, b"iloc")?.ok_or(exif::Error::NotFound("HEIF iloc box not found"))?;
+    let (offset, length) = find_item_location(iloc, item_id)?
+        .ok_or(exif::Error::NotFound("Exif item location not found"))?;
+
+    let end = offset.checked_add(length).ok_or(TRUNCATED)?;
+    let item = data.get(offset..end).ok_or(TRUNCATED)?;
+
+    // The item starts with the offset to the TIFF header, which is usually
+    // preceded by the "Exif\0\0" marker.
+    let header_offset = read_uint(item, 0, 4)? as usize;
+    item.get(header_offset.checked_add(4).ok_or(TRUNCATED)?..).ok_or(TRUNCATED)
+}
+
+fn read_uint(data: &[u8], pos: usize, size: usize) -> Result<u64, exif::Error> {
+    let end = pos.checked_add(size).ok_or(TRUNCATED)?;
+    let bytes = data.get(pos..end).ok_or(TRUNCATED)?;
+    Ok(bytes.iter().fold(0, |value, &b| value << 8 | u64::from(b)))
+}
+
+/// Reads the box starting at `pos`. Returns the box type, its contents and
+/// the position of the next box.
+fn read_box(data: &[u8], pos: usize) -> Result<(&[u8], &[u8], usize), exif::Error> {
+    let mut size = read_uint(data, pos, 4)? as usize;
+    let mut header = 8;
+
+    if size == 1 {
+        size = read_uint(data, pos.checked_add(8).ok_or(TRUNCATED)?, 8)? as usize;
+        header = 16;
+    } else if size == 0 {
+        size = data.len() - pos;
+    }
+
+    let end = pos.checked_add(size).ok_or(TRUNCATED)?;
+    if size < header || end > data.len() {
+        return Err(TRUNCATED);
+    }
+
+    Ok((&data[pos + 4..pos + 8], &data[pos + header..end], end))
+}
+
+/// Returns the contents of the first box of the given type.
+fn find_box<'a>(data: &'a [u8], box_type: &[u8]) -> Result<Option<&'a [u8]>, exif::Error> {
+    let mut pos = 0;
+
+    while pos + 8 <= data.len() {
+        let (type_, contents, next) = read_box(data, pos)?;
+        if type_ == box_type {
+            return Ok(Some(contents));
+        }
+        pos = next;
+    }
+
+    Ok(None)
+}
+
+/// Returns the id of the item of type `Exif` in an `iinf` box.
+fn find_exif_item(iinf: &[u8]) -> Result<Option<u32>, exif::Error> {
+    let version = *iinf.first().ok_or(TRUNCATED)?;
+    let (entry_count, mut pos) = if version == 0 {
+        (read_uint(iinf, 4, 2)?, 6)
+    } else {
+        (read_uint(iinf, 4, 4)?, 8)
+    };
+
+    for _ in 0..entry_count {
+        let (type_, infe, next) = read_box(iinf, pos)?;
+        pos = next;
+
+        if type_ != b"infe" {
+            continue;
+        }
+
+        // Versions before 2 do not carry an item type.
+        let version = *infe.first().ok_or(TRUNCATED)?;
+        let (item_id, type_pos) = match version {
+            0 | 1 => continue,
+            2 => (read_uint(infe, 4, 2)? as u32, 8),
+            _ => (read_uint(infe, 4, 4)? as u32, 10),
+        };
+
+        if infe.get(type_pos..type_pos + 4) == Some(b"Exif") {
+            return Ok(Some(item_id));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns the file offset and length of an item described in an `iloc` box.
+fn find_item_location(iloc: &[u8], item_id: u32) -> Result<Option<(usize, usize)>, exif::Error> {
+    let version = *iloc.first().ok_or(TRUNCATED)?;
+    let sizes = read_uint(iloc, 4, 1)? as usize;
+    let (offset_size, length_size) = (sizes >> 4, sizes & 0xf);
+    let sizes = read_uint(iloc, 5, 1)? as usize;
+    let base_offset_size = sizes >> 4;
+    let index_size = if version == 1 || version == 2 { sizes & 0xf } else { 0 };
+
+    let id_size = if version < 2 { 2 } else { 4 };
+    let item_count = read_uint(iloc, 6, id_size)?;
+    let mut pos = 6 + id_size;
+
+    for _ in 0..item_count {
+        let id = read_uint(iloc, pos, id_size)?;
+        pos += id_size;
+
+        let mut construction_method = 0;
+        if version == 1 || version == 2 {
+            construction_method = read_uint(iloc, pos, 2)? & 0xf;
+            pos += 2;
+        }
+
+        // data_reference_index
+        pos += 2;
+
+        let base_offset = read_uint(iloc, pos, base_offset_size)?;
+        pos += base_offset_size;
+
+        let extent_count = read_uint(iloc, pos, 2)?;
+        pos += 2;
+
+        let mut location = None;
+
+        for _ in 0..extent_count {
+            pos += index_size;
+            let offset = read_uint(iloc, pos, offset_size)?;
+            pos += offset_size;
+            let length = read_uint(iloc, pos, length_size)?;
+            pos += length_size;
+
+            if location.is_none() {
+                let offset = base_offset.checked_add(offset).ok_or(TRUNCATED)?;
+                location = Some((offset as usize, length as usize));
+            }
+        }
+
+        if id == u64::from(item_id) {
+            if construction_method != 0 {
+                return Err(exif::Error::NotSupported("HEIF item construction method"));
+            }
+            return Ok(location);
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIFF: &[u8] = b"MM\0\x2a\0\0\0\x08";
+    const EXIF_ID: u32 = 2;
+
+    fn make_box(box_type: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut data = (contents.len() as u32 + 8).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(contents);
+        data
+    }
+
+    fn item_id(version: u8, id: u32) -> Vec<u8> {
+        if version < 3 { (id as u16).to_be_bytes().to_vec() } else { id.to_be_bytes().to_vec() }
+    }
+
+    fn infe(version: u8, id: u32, item_type: &[u8]) -> Vec<u8> {
+        let contents = [&[version, 0, 0, 0][..], &item_id(version, id), &[0, 0], item_type, b"\0"].concat();
+        make_box(b"infe", &contents)
+    }
+
+    fn iinf(infe_version: u8) -> Vec<u8> {
+        let entries = [infe(infe_version, 1, b"hvc1"), infe(infe_version, EXIF_ID, b"Exif")].concat();
+        let header = if infe_version < 3 {
+            vec![0, 0, 0, 0, 0, 2]
+        } else {
+            vec![1, 0, 0, 0, 0, 0, 0, 2]
+        };
+        make_box(b"iinf", &[header, entries].concat())
+    }
+
+    /// An `iloc` box with 4-byte base offsets, offsets and lengths and one
+    /// extent per item.
+    fn iloc(version: u8, items: &[(u32, u32, u32, u32)]) -> Vec<u8> {
+        let mut contents = vec![version, 0, 0, 0, 0x44, 0x40];
+        contents.extend_from_slice(&(items.len() as u16).to_be_bytes());
+        for &(id, base_offset, offset, length) in items {
+            contents.extend_from_slice(&(id as u16).to_be_bytes());
+            if version == 1 {
+                contents.extend_from_slice(&[0, 0]);
+            }
+            contents.extend_from_slice(&[0, 0]);
+            contents.extend_from_slice(&base_offset.to_be_bytes());
+            contents.extend_from_slice(&[0, 1]);
+            contents.extend_from_slice(&offset.to_be_bytes());
+            contents.extend_from_slice(&length.to_be_bytes());
+        }
+        make_box(b"iloc", &contents)
+    }
+
+    /// Builds a HEIF file whose Exif item follows the `meta` box. Version 1
+    /// `iloc` boxes locate the item relative to a base offset.
+    fn heif(infe_version: u8, iloc_version: u8) -> Vec<u8> {
+        let ftyp = make_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let item = [&[0, 0, 0, 6][..], b"Exif\0\0", TIFF].concat();
+
+        let meta = |offset: u32| {
+            let base_offset = if iloc_version == 1 { 8 } else { 0 };
+            let items = [(1, 0, 0, 0), (EXIF_ID, base_offset, offset - base_offset, item.len() as u32)];
+            let contents = [&[0, 0, 0, 0][..], &iinf(infe_version), &iloc(iloc_version, &items)].concat();
+            make_box(b"meta", &contents)
+        };
+
+        // The size of the meta box does not depend on the item offset.
+        let offset = (ftyp.len() + meta(0xffff).len()) as u32;
+        [ftyp, meta(offset), item].concat()
+    }
+
+    #[test]
+    fn heif_brand() {
+        assert!(is_heif(&heif(2, 0)));
+        assert!(!is_heif(&make_box(b"ftyp", b"isom\0\0\0\0")));
+        assert!(!is_heif(b"\0\0\0\x08ftyp"));
+    }
+
+    #[test]
+    fn infe_version_2() {
+        assert_eq!(extract_exif(&heif(2, 0)).unwrap(), TIFF);
+    }
+
+    #[test]
+    fn infe_version_3() {
+        assert_eq!(extract_exif(&heif(3, 0)).unwrap(), TIFF);
+    }
+
+    #[test]
+    fn iloc_version_1_with_base_offset() {
+        assert_eq!(extract_exif(&heif(2, 1)).unwrap(), TIFF);
+        assert_eq!(extract_exif(&heif(3, 1)).unwrap(), TIFF);
+    }
+
+    #[test]
+    fn no_exif_item() {
+        let ftyp = make_box(b"ftyp", b"heic\0\0\0\0");
+        let iinf = make_box(b"iinf", &[&[0, 0, 0, 0, 0, 1][..], &infe(2, 1, b"hvc1")].concat());
+        let meta = make_box(b"meta", &[&[0, 0, 0, 0][..], &iinf].concat());
+        let data = [ftyp, meta].concat();
+        assert!(matches!(extract_exif(&data), Err(exif::Error::NotFound("Exif item not found"))));
+    }
+
+    #[test]
+    fn truncated() {
+        let data = heif(2, 1);
+
+        // Cut in the middle of the meta box.
+        assert!(matches!(extract_exif(&data[..40]), Err(exif::Error::InvalidFormat("truncated HEIF box"))));
+
+        // Cut in the middle of the Exif item.
+        assert!(matches!(extract_exif(&data[..data.len() - 1]), Err(exif::Error::InvalidFormat("truncated HEIF box"))));
+
+        // A box larger than the file.
+        let mut data = make_box(b"meta", &[0, 0, 0, 0]);
+        data[3] = 0xff;
+        assert!(matches!(extract_exif(&data), Err(exif::Error::InvalidFormat("truncated HEIF box"))));
+    }
+}
@@ -0,0 +1,204 @@
+/*
+    Copyright (C) 2018 Oskari Timperi <oskari.timperi@iki.fi>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Extraction of the Exif and XMP segments from JPEG images.
+//!
+//! Both are stored in APP1 segments, which must come before the image data.
+//! Reading stops at the start of the image data, so only the headers of the
+//! file are read. Data appended after the image, such as the video of
+//! Android motion photos, is never read.
+
+use std::io::{self, Read};
+
+use exif;
+
+const EXIF_ID: &[u8] = b"Exif\0\0";
+const XMP_ID: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOS: u8 = 0xda;
+const APP1: u8 = 0xe1;
+
+/// The metadata segments of a JPEG file.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    /// TIFF data of the Exif segment.
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet of the XMP segment.
+    pub xmp: Option<Vec<u8>>,
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+/// Returns the Exif and XMP segments of a JPEG file.
+pub fn read_metadata<R: Read>(reader: &mut R) -> Result<Metadata, exif::Error> {
+    let mut metadata = Metadata::default();
+    match read_segments(reader, &mut metadata) {
+        Ok(()) => Ok(metadata),
+        // Files that are broken after the Exif segment are still usable.
+        Err(exif::Error::InvalidFormat(_)) if metadata.exif.is_some() => Ok(metadata),
+        Err(exif::Error::Io(ref error)) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            if metadata.exif.is_some() {
+                Ok(metadata)
+            } else {
+                Err(exif::Error::InvalidFormat("Broken JPEG file"))
+            }
+        },
+        Err(error) => Err(error),
+    }
+}
+
+fn read_segments<R: Read>(reader: &mut R, metadata: &mut Metadata) -> Result<(), exif::Error> {
+    if read_u8(reader)? != 0xff || read_u8(reader)? != SOI {
+        return Err(exif::Error::InvalidFormat("Not a JPEG file"));
+    }
+
+    loop {
+        if read_u8(reader)? != 0xff {
+            return Err(exif::Error::InvalidFormat("Invalid JPEG marker"));
+        }
+
+        // Markers may be preceded by any number of fill bytes.
+        let mut code = read_u8(reader)?;
+        while code == 0xff {
+            code = read_u8(reader)?;
+        }
+
+        match code {
+            // Standalone markers have no length.
+            0x01 | 0xd0..=0xd7 => continue,
+            SOS | EOI => return Ok(()),
+            _ => {},
+        }
+
+        let mut length = [0; 2];
+        reader.read_exact(&mut length)?;
+        let length = u16::from_be_bytes(length);
+        if length < 2 {
+            return Err(exif::Error::InvalidFormat("Invalid segment length"));
+        }
+
+        if code == APP1 {
+            let mut data = vec![0; usize::from(length) - 2];
+            reader.read_exact(&mut data)?;
+            if data.starts_with(EXIF_ID) && metadata.exif.is_none() {
+                metadata.exif = Some(data.split_off(EXIF_ID.len()));
+            } else if data.starts_with(XMP_ID) && metadata.xmp.is_none() {
+                metadata.xmp = Some(data.split_off(XMP_ID.len()));
+            }
+        } else {
+            io::copy(&mut reader.by_ref().take(u64::from(length) - 2), &mut io::sink())?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIFF: &[u8] = b"MM\0\x2a\0\0\0\x08";
+    const PACKET: &[u8] = b"<x:xmpmeta/>";
+    const IMAGE: &[u8] = &[0xff, SOS, 0, 2, 0xff, EOI];
+
+    fn segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    fn exif_segment() -> Vec<u8> {
+        segment(APP1, &[EXIF_ID, TIFF].concat())
+    }
+
+    fn xmp_segment() -> Vec<u8> {
+        segment(APP1, &[XMP_ID, PACKET].concat())
+    }
+
+    fn read(data: &[u8]) -> Result<Metadata, exif::Error> {
+        read_metadata(&mut io::Cursor::new(data))
+    }
+
+    #[test]
+    fn fill_bytes() {
+        let data = [&[0xff, SOI, 0xff, 0xff, 0xff][..], &exif_segment(), IMAGE].concat();
+        assert_eq!(read(&data).unwrap().exif.unwrap(), TIFF);
+    }
+
+    #[test]
+    fn standalone_markers() {
+        let data = [&[0xff, SOI, 0xff, 0x01, 0xff, 0xd0, 0xff, 0xd7][..], &exif_segment(), IMAGE].concat();
+        assert_eq!(read(&data).unwrap().exif.unwrap(), TIFF);
+    }
+
+    #[test]
+    fn exif_and_xmp() {
+        let data = [&[0xff, SOI][..], &xmp_segment(), &exif_segment(), IMAGE].concat();
+        let metadata = read(&data).unwrap();
+        assert_eq!(metadata.exif.unwrap(), TIFF);
+        assert_eq!(metadata.xmp.unwrap(), PACKET);
+    }
+
+    #[test]
+    fn other_app1() {
+        let other = segment(APP1, b"http://ns.adobe.com/xmp/extension/\0");
+
+        let data = [&[0xff, SOI][..], &other, &xmp_segment(), IMAGE].concat();
+        let metadata = read(&data).unwrap();
+        assert!(metadata.exif.is_none());
+        assert_eq!(metadata.xmp.unwrap(), PACKET);
+
+        let data = [&[0xff, SOI][..], &other, IMAGE].concat();
+        let metadata = read(&data).unwrap();
+        assert!(metadata.exif.is_none() && metadata.xmp.is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(matches!(read(&[0xff, SOI]), Err(exif::Error::InvalidFormat("Broken JPEG file"))));
+
+        let data = exif_segment();
+        let data = [&[0xff, SOI][..], &data[..data.len() - 1]].concat();
+        assert!(matches!(read(&data), Err(exif::Error::InvalidFormat("Broken JPEG file"))));
+
+        // The Exif data is kept if the file is cut short after it.
+        let data = [&[0xff, SOI][..], &exif_segment(), &[0xff]].concat();
+        assert_eq!(read(&data).unwrap().exif.unwrap(), TIFF);
+    }
+
+    /// A reader of data that must not be read.
+    struct Unreachable;
+
+    impl Read for Unreachable {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            panic!("appended data was read");
+        }
+    }
+
+    #[test]
+    fn appended_data() {
+        let data = [&[0xff, SOI][..], &exif_segment(), &xmp_segment(), IMAGE].concat();
+        let metadata = read_metadata(&mut io::Cursor::new(data).chain(Unreachable)).unwrap();
+        assert_eq!(metadata.exif.unwrap(), TIFF);
+        assert_eq!(metadata.xmp.unwrap(), PACKET);
+    }
+}
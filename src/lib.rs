@@ -0,0 +1,2457 @@
+/*
+    Copyright (C) 2018 Oskari Timperi <oskari.timperi@iki.fi>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+extern crate geojson;
+extern crate geo_types;
+extern crate exif;
+extern crate serde;
+extern crate serde_json;
+extern crate sha1;
+extern crate sha2;
+extern crate rayon;
+extern crate base64;
+extern crate chrono;
+extern crate chrono_tz;
+#[macro_use]
+extern crate clap;
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use geojson::{Feature, GeoJson, Geometry, Value, FeatureCollection};
+use serde_json::{Map, Value as JsonValue};
+use base64::Engine;
+use rayon::prelude::*;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+mod gpx;
+mod heif;
+mod jpeg;
+mod xmp;
+
+/// Errors that can occur while extracting a feature from a photo.
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    IoError(std::io::Error),
+    Utf8Error(std::str::Utf8Error),
+    FieldMissing(exif::Tag),
+    InvalidField(exif::Tag, &'static str),
+    ExifError(exif::Error),
+    UnsupportedFormat(&'static str),
+}
+
+impl Error {
+    /// Returns true if the error was caused by the photo not having a GPS
+    /// position.
+    pub fn is_missing_gps(&self) -> bool {
+        matches!(self, Error::FieldMissing(exif::Tag::GPSLatitude)
+            | Error::FieldMissing(exif::Tag::GPSLatitudeRef)
+            | Error::FieldMissing(exif::Tag::GPSLongitude)
+            | Error::FieldMissing(exif::Tag::GPSLongitudeRef))
+    }
+
+    /// Returns a short name for the kind of the error.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::IoError(_) => "io",
+            Error::Utf8Error(_) => "utf8",
+            Error::FieldMissing(_) => "field_missing",
+            Error::InvalidField(..) => "invalid_field",
+            Error::ExifError(_) => "exif",
+            Error::UnsupportedFormat(_) => "unsupported_format",
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IoError(error) => write!(f, "{}", error),
+            Error::Utf8Error(error) => write!(f, "{}", error),
+            Error::FieldMissing(tag) => write!(f, "missing field: {}", tag),
+            Error::InvalidField(tag, msg) => write!(f, "invalid field {}: {}", tag, msg),
+            Error::ExifError(error) => write!(f, "{}", error),
+            Error::UnsupportedFormat(format) => write!(f, "unsupported file format: {}", format),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(e) => Some(e),
+            Error::Utf8Error(e) => Some(e),
+            Error::ExifError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(value: std::str::Utf8Error) -> Error {
+        Error::Utf8Error(value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Error {
+        Error::IoError(value)
+    }
+}
+
+impl From<exif::Error> for Error {
+    fn from(value: exif::Error) -> Error {
+        Error::ExifError(value)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Returns the name of the image format recognized from the magic bytes at
+/// the start of a file.
+fn detect_format(data: &[u8]) -> &'static str {
+    if data.starts_with(b"\xff\xd8") {
+        "JPEG"
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        "TIFF"
+    } else if heif::is_heif(data) {
+        "HEIF"
+    } else if data.starts_with(b"\x89PNG") {
+        "PNG"
+    } else if data.starts_with(b"GIF8") {
+        "GIF"
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        "WebP"
+    } else {
+        "unknown"
+    }
+}
+
+/// Returns true if the file looks like an image, judging by its first bytes.
+/// Files that can't be read are considered candidates so that the error is
+/// reported when the file is processed.
+pub fn is_image_candidate(path: &Path) -> bool {
+    let mut header = [0; 16];
+    let read = std::fs::File::open(path).and_then(|mut file| file.read(&mut header));
+    match read {
+        Ok(len) => detect_format(&header[..len]) != "unknown",
+        Err(_) => true,
+    }
+}
+
+/// The EXIF data of a file, or the reason it has none, and its XMP packet.
+type Metadata = (std::result::Result<exif::Reader, exif::Error>, Option<Vec<u8>>);
+
+/// Reads the EXIF data and the XMP packet of a file. JPEG, HEIF and
+/// TIFF-based files are supported. The latter include raw formats such as
+/// DNG, CR2 and NEF, which are TIFF files with the EXIF data in the same
+/// IFDs.
+fn read_metadata(filename: &Path) -> Result<Metadata> {
+    let file = std::fs::File::open(filename)?;
+    let mut file = std::io::BufReader::new(file);
+
+    match detect_format(file.fill_buf()?) {
+        "JPEG" => {
+            let metadata = jpeg::read_metadata(&mut file)?;
+            let reader = match metadata.exif {
+                Some(tiff) => Ok(exif::Reader::new(&mut std::io::Cursor::new(tiff))?),
+                None => Err(exif::Error::NotFound("No Exif data found")),
+            };
+            Ok((reader, metadata.xmp))
+        },
+        "TIFF" => Ok((Ok(exif::Reader::new(&mut file)?), None)),
+        "HEIF" => {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            let reader = match heif::extract_exif(&data) {
+                Ok(tiff) => Ok(exif::Reader::new(&mut std::io::Cursor::new(tiff))?),
+                Err(error @ exif::Error::NotFound(_)) => Err(error),
+                Err(error) => return Err(error.into()),
+            };
+            // The whole file is read anyway, so the packet is looked up
+            // directly.
+            let xmp = xmp::find_packet(&data).map(|packet| packet.as_bytes().to_vec());
+            Ok((reader, xmp))
+        },
+        format => Err(Error::UnsupportedFormat(format)),
+    }
+}
+
+fn get_degrees(reader: &exif::Reader, tag: exif::Tag) -> Result<f64> {
+    let field = reader.get_field(tag, false).ok_or(Error::FieldMissing(tag))?;
+
+    let dms: Vec<(f64, f64)> = match field.value {
+        exif::Value::Rational(ref dms) => dms.iter().map(|r| (f64::from(r.num), f64::from(r.denom))).collect(),
+        // Some non-conforming files store signed components.
+        exif::Value::SRational(ref dms) => dms.iter().map(|r| (f64::from(r.num), f64::from(r.denom))).collect(),
+        _ => return Err(Error::InvalidField(tag, "invalid field type")),
+    };
+
+    if dms.len() != 3 {
+        return Err(Error::InvalidField(tag, "expected 3 rationals"))
+    }
+
+    let mut components = [0.0; 3];
+    for (component, &(num, denom)) in components.iter_mut().zip(&dms) {
+        if denom == 0.0 {
+            // Some cameras write 0/0 for components they don't use.
+            if num != 0.0 {
+                return Err(Error::InvalidField(tag, "zero denominator"))
+            }
+            continue;
+        }
+        // The sign comes from the ref tag only, so that components which
+        // are already negative are not negated twice.
+        *component = (num / denom).abs();
+    }
+    let [degrees, min, sec] = components;
+    Ok(degrees + min/60.0 + sec/3600.0)
+}
+
+/// Returns the value of an ASCII field. Fields with several strings are
+/// joined with spaces.
+fn get_string(reader: &exif::Reader, tag: exif::Tag) -> Result<Cow<'_, str>> {
+    let field = reader.get_field(tag, false).ok_or(Error::FieldMissing(tag))?;
+    if let exif::Value::Ascii(ref strings) = field.value {
+        match strings[..] {
+            [] => Err(Error::InvalidField(tag, "empty string")),
+            [s] => Ok(Cow::Borrowed(std::str::from_utf8(s)?)),
+            _ => {
+                let strings = strings.iter()
+                    .map(|s| std::str::from_utf8(s))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                Ok(Cow::Owned(strings.join(" ")))
+            }
+        }
+    } else {
+        Err(Error::InvalidField(tag, "field is not a string"))
+    }
+}
+
+fn get_rational(reader: &exif::Reader, tag: exif::Tag) -> Result<f64> {
+    let field = reader.get_field(tag, false).ok_or(Error::FieldMissing(tag))?;
+    match field.value {
+        exif::Value::Rational(ref v) if !v.is_empty() => {
+            if v[0].denom == 0 {
+                return Err(Error::InvalidField(tag, "zero denominator"))
+            }
+            Ok(v[0].to_f64())
+        },
+        _ => Err(Error::InvalidField(tag, "field is not a rational"))
+    }
+}
+
+fn get_short(reader: &exif::Reader, tag: exif::Tag) -> Result<u16> {
+    let field = reader.get_field(tag, false).ok_or(Error::FieldMissing(tag))?;
+    match field.value {
+        exif::Value::Short(ref v) if !v.is_empty() => Ok(v[0]),
+        _ => Err(Error::InvalidField(tag, "field is not a short"))
+    }
+}
+
+/// Returns the text of an UNDEFINED field that starts with the 8-byte
+/// character code used by e.g. GPSProcessingMethod and UserComment. Text
+/// without a known character code is read as UTF-8. JIS encoded text is not
+/// supported.
+fn get_encoded_string(reader: &exif::Reader, tag: exif::Tag) -> Option<String> {
+    let field = reader.get_field(tag, false)?;
+    let data = match field.value {
+        exif::Value::Undefined(data, _) => data,
+        _ => return None,
+    };
+
+    let text = match data.split_at(data.len().min(8)) {
+        (b"ASCII\0\0\0", text) | (b"\0\0\0\0\0\0\0\0", text) => String::from_utf8_lossy(text).into_owned(),
+        (b"UNICODE\0", text) => {
+            let units: Vec<u16> = text.chunks_exact(2)
+                .map(|unit| if reader.little_endian() {
+                    u16::from_le_bytes([unit[0], unit[1]])
+                } else {
+                    u16::from_be_bytes([unit[0], unit[1]])
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        },
+        (b"JIS\0\0\0\0\0", _) => return None,
+        _ => String::from_utf8_lossy(data).into_owned(),
+    };
+
+    let text = text.trim_end_matches('\0').trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+fn get_number(reader: &exif::Reader, tag: exif::Tag) -> Option<f64> {
+    let field = reader.get_field(tag, false)?;
+    match field.value {
+        exif::Value::Rational(ref v) if !v.is_empty() && v[0].denom != 0 => Some(v[0].to_f64()),
+        ref value => value.get_uint(0).map(f64::from),
+    }
+}
+
+/// Converts a number to JSON, keeping integral values as integers.
+fn number_value(value: f64) -> JsonValue {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        JsonValue::from(value as i64)
+    } else {
+        JsonValue::from(value)
+    }
+}
+
+fn get_datetime(reader: &exif::Reader) -> Result<exif::DateTime> {
+    let tag = exif::Tag::DateTimeOriginal;
+    let data = get_string(reader, tag)?;
+    let mut datetime = exif::DateTime::from_ascii(data.as_bytes())?;
+
+    let valid = (1..=12).contains(&datetime.month) && (1..=31).contains(&datetime.day)
+        && datetime.hour < 24 && datetime.minute < 60 && datetime.second <= 60;
+    if !valid {
+        return Err(Error::InvalidField(tag, "date or time out of range"));
+    }
+
+    if let Ok(offset) = get_string(reader, exif::Tag::OffsetTimeOriginal) {
+        // A blank or malformed offset leaves the datetime without one.
+        let _ = datetime.parse_offset(offset.as_bytes());
+    }
+
+    Ok(datetime)
+}
+
+/// Converts a datetime to a chrono datetime, ignoring its offset. Leap
+/// seconds are clamped to the preceding second.
+fn naive_datetime(datetime: &exif::DateTime) -> Option<chrono::NaiveDateTime> {
+    chrono::NaiveDate::from_ymd_opt(i32::from(datetime.year), u32::from(datetime.month), u32::from(datetime.day))?
+        .and_hms_opt(u32::from(datetime.hour), u32::from(datetime.minute), u32::from(datetime.second.min(59)))
+}
+
+/// Returns the time the photo was taken in seconds since the Unix epoch.
+/// Datetimes without an offset are in the given timezone, or in UTC if there
+/// is none.
+fn get_timestamp(reader: &exif::Reader, timezone: Option<Timezone>) -> Option<i64> {
+    let datetime = get_datetime(reader).ok()?;
+    let offset = datetime.offset
+        .or_else(|| timezone.and_then(|tz| tz.offset_at(&datetime)))
+        .unwrap_or(0);
+    let local = naive_datetime(&datetime)?;
+    Some(local.and_utc().timestamp() - i64::from(offset) * 60)
+}
+
+/// Formats a datetime as RFC 3339. The offset is left out if it is not known.
+fn format_datetime(datetime: &exif::DateTime) -> String {
+    let mut formatted = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        datetime.year, datetime.month, datetime.day,
+        datetime.hour, datetime.minute, datetime.second);
+
+    if let Some(offset) = datetime.offset {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.abs();
+        formatted.push_str(&format!("{}{:02}:{:02}", sign, offset / 60, offset % 60));
+    }
+
+    formatted
+}
+
+/// Formats a system time as an RFC 3339 UTC datetime.
+fn format_system_time(time: std::time::SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn get_gps_datetime(reader: &exif::Reader) -> Option<String> {
+    let date = get_string(reader, exif::Tag::GPSDateStamp).ok()?;
+    let field = reader.get_field(exif::Tag::GPSTimeStamp, false)?;
+
+    let hms = match field.value {
+        exif::Value::Rational(ref hms) if hms.len() == 3 && hms.iter().all(|r| r.denom != 0) => hms,
+        _ => return None,
+    };
+
+    let bytes = date.as_bytes();
+    if bytes.len() != 10 || bytes[4] != b':' || bytes[7] != b':' {
+        return None;
+    }
+
+    Some(format!("{}T{:02}:{:02}:{:02}Z", date.replace(':', "-"),
+        hms[0].to_f64() as u32, hms[1].to_f64() as u32, hms[2].to_f64() as u32))
+}
+
+/// Reads a coordinate from a degrees tag and its reference tag. The
+/// coordinate is negated if the reference is `negative_ref`, e.g. "south",
+/// or its initial. The reference is compared case-insensitively.
+fn get_coordinate(reader: &exif::Reader, tag: exif::Tag, ref_tag: exif::Tag, negative_ref: &str) -> Result<f64> {
+    let coordinate = get_degrees(reader, tag)?;
+    let ref_ = get_string(reader, ref_tag)?;
+    let ref_ = ref_.trim();
+    if ref_.eq_ignore_ascii_case(negative_ref) || ref_.eq_ignore_ascii_case(&negative_ref[..1]) {
+        Ok(-coordinate)
+    } else {
+        Ok(coordinate)
+    }
+}
+
+fn get_latitude(reader: &exif::Reader) -> Result<f64> {
+    get_coordinate(reader, exif::Tag::GPSLatitude, exif::Tag::GPSLatitudeRef, "south")
+}
+
+fn get_longitude(reader: &exif::Reader) -> Result<f64> {
+    get_coordinate(reader, exif::Tag::GPSLongitude, exif::Tag::GPSLongitudeRef, "west")
+}
+
+/// Returns the latitude and longitude of a photo. If the EXIF data has no
+/// GPS position, it is read from the XMP packet instead. TIFF files keep the
+/// packet in the XMLPacket tag.
+fn get_location(reader: &exif::Reader, xmp: Option<&[u8]>) -> Result<(f64, f64)> {
+    match get_latitude(reader).and_then(|latitude| Ok((latitude, get_longitude(reader)?))) {
+        Err(error) if error.is_missing_gps() => {
+            let packet = xmp.or_else(|| match reader.get_field(XML_PACKET, false)?.value {
+                exif::Value::Byte(ref data) => Some(data),
+                exif::Value::Undefined(data, _) => Some(data),
+                _ => None,
+            });
+            packet.map(get_xmp_location).transpose()?.flatten().ok_or(error)
+        },
+        result => result,
+    }
+}
+
+/// The TIFF tag of the XMP packet.
+const XML_PACKET: exif::Tag = exif::Tag(exif::Context::Tiff, 700);
+
+/// Reads the latitude and longitude from an XMP packet, if it has both.
+fn get_xmp_location(data: &[u8]) -> Result<Option<(f64, f64)>> {
+    let packet = match xmp::find_packet(data) {
+        Some(packet) => packet,
+        None => return Ok(None),
+    };
+
+    let coordinate = |tag: exif::Tag| match xmp::get_property(packet, &format!("exif:{}", tag)) {
+        Some(value) => xmp::parse_coordinate(value)
+            .map(Some)
+            .ok_or(Error::InvalidField(tag, "invalid XMP coordinate")),
+        None => Ok(None),
+    };
+
+    Ok(coordinate(exif::Tag::GPSLatitude)?.zip(coordinate(exif::Tag::GPSLongitude)?))
+}
+
+/// Returns a reader without any fields, for photos that have no EXIF data.
+fn empty_exif() -> Result<exif::Reader> {
+    // A big-endian TIFF header followed by an IFD without entries.
+    let tiff = b"MM\0\x2a\0\0\0\x08\0\0\0\0\0\0";
+    Ok(exif::Reader::new(&mut std::io::Cursor::new(&tiff[..]))?)
+}
+
+/// Returns the altitude and whether it is below sea level. The altitude is
+/// negative below sea level. A missing GPSAltitudeRef means above sea level.
+fn get_altitude(reader: &exif::Reader) -> Option<(f64, bool)> {
+    // An altitude with a zero denominator is treated as missing.
+    let mut altitude = get_rational(reader, exif::Tag::GPSAltitude).ok()?;
+    let below_sea_level = reader.get_field(exif::Tag::GPSAltitudeRef, false)
+        .and_then(|field| field.value.get_uint(0)) == Some(1);
+    if below_sea_level {
+        altitude = -altitude;
+    }
+    Some((altitude, below_sea_level))
+}
+
+/// Renders a LensSpecification value, e.g. "24-70mm f/2.8-4". Unknown
+/// components are written as 0/0 and are left out.
+fn format_lens_specification(reader: &exif::Reader) -> Option<String> {
+    let field = reader.get_field(exif::Tag::LensSpecification, false)?;
+    let values: Vec<Option<f64>> = match field.value {
+        exif::Value::Rational(ref v) if v.len() == 4 => {
+            v.iter().map(|r| if r.denom == 0 || r.num == 0 { None } else { Some(r.to_f64()) }).collect()
+        },
+        _ => return None,
+    };
+
+    let range = |min: Option<f64>, max: Option<f64>| match (min, max) {
+        (Some(min), Some(max)) if min != max => Some(format!("{}-{}", min, max)),
+        (Some(value), _) | (None, Some(value)) => Some(value.to_string()),
+        (None, None) => None,
+    };
+
+    let parts: Vec<String> = vec![
+        range(values[0], values[1]).map(|focal| format!("{}mm", focal)),
+        range(values[2], values[3]).map(|fnumber| format!("f/{}", fnumber)),
+    ].into_iter().flatten().collect();
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" "))
+    }
+}
+
+/// Returns the lens model, or if it is missing, a description built from the
+/// lens make and specification.
+fn get_lens(reader: &exif::Reader) -> Option<String> {
+    if let Ok(model) = get_string(reader, exif::Tag::LensModel) {
+        return Some(model.into_owned());
+    }
+
+    let parts: Vec<String> = vec![
+        get_string(reader, exif::Tag::LensMake).ok().map(Cow::into_owned),
+        format_lens_specification(reader),
+    ].into_iter().flatten().collect();
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" "))
+    }
+}
+
+/// Returns the mode encoded in bits 3 and 4 of a Flash value.
+fn flash_mode(flash: u16) -> &'static str {
+    match (flash >> 3) & 0x3 {
+        1 => "on",
+        2 => "off",
+        3 => "auto",
+        _ => "unknown",
+    }
+}
+
+fn metering_mode(value: u16) -> Option<&'static str> {
+    match value {
+        0 => Some("unknown"),
+        1 => Some("average"),
+        2 => Some("center-weighted"),
+        3 => Some("spot"),
+        4 => Some("multi-spot"),
+        5 => Some("matrix"),
+        6 => Some("partial"),
+        255 => Some("other"),
+        _ => None,
+    }
+}
+
+fn exposure_program(value: u16) -> Option<&'static str> {
+    match value {
+        0 => Some("not-defined"),
+        1 => Some("manual"),
+        2 => Some("normal"),
+        3 => Some("aperture-priority"),
+        4 => Some("shutter-priority"),
+        5 => Some("creative"),
+        6 => Some("action"),
+        7 => Some("portrait"),
+        8 => Some("landscape"),
+        _ => None,
+    }
+}
+
+fn scene_capture_type(value: u16) -> Option<&'static str> {
+    match value {
+        0 => Some("standard"),
+        1 => Some("landscape"),
+        2 => Some("portrait"),
+        3 => Some("night"),
+        _ => None,
+    }
+}
+
+fn color_space(value: u16) -> Option<&'static str> {
+    match value {
+        1 => Some("sRGB"),
+        0xffff => Some("uncalibrated"),
+        _ => None,
+    }
+}
+
+/// Returns the subject distance in meters. A distance of 0 means it is
+/// unknown, and a numerator of 0xFFFFFFFF means infinity, which is returned
+/// as the string `infinity`.
+fn get_subject_distance(reader: &exif::Reader) -> Option<JsonValue> {
+    let field = reader.get_field(exif::Tag::SubjectDistance, false)?;
+    match field.value {
+        exif::Value::Rational(ref v) if !v.is_empty() => match v[0] {
+            exif::Rational { num: 0xffff_ffff, .. } => Some("infinity".into()),
+            exif::Rational { num: 0, .. } | exif::Rational { denom: 0, .. } => None,
+            distance => Some(number_value(distance.to_f64())),
+        },
+        _ => None,
+    }
+}
+
+/// Returns the bits per sample as a single number if every sample has the
+/// same size, and as an array of the sizes otherwise.
+fn get_bits_per_sample(reader: &exif::Reader) -> Option<JsonValue> {
+    let field = reader.get_field(exif::Tag::BitsPerSample, false)?;
+    match field.value {
+        exif::Value::Short(ref bits) if !bits.is_empty() => {
+            if bits.iter().all(|&b| b == bits[0]) {
+                Some(bits[0].into())
+            } else {
+                Some(bits.iter().map(|&b| JsonValue::from(b)).collect())
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Returns the name of a coded value, or the value itself if it has no name.
+fn decode_short(value: u16, names: fn(u16) -> Option<&'static str>) -> JsonValue {
+    match names(value) {
+        Some(name) => name.into(),
+        None => value.into(),
+    }
+}
+
+fn white_balance(value: u16) -> Option<&'static str> {
+    match value {
+        0 => Some("auto"),
+        1 => Some("manual"),
+        _ => None,
+    }
+}
+
+/// Returns the embedded JPEG thumbnail as a data URI.
+fn get_thumbnail(reader: &exif::Reader) -> Option<String> {
+    let offset = reader.get_field(exif::Tag::JPEGInterchangeFormat, true)?.value.get_uint(0)? as usize;
+    let length = reader.get_field(exif::Tag::JPEGInterchangeFormatLength, true)?.value.get_uint(0)? as usize;
+    let thumbnail = reader.buf().get(offset..offset.checked_add(length)?)?;
+
+    Some(format!("data:image/jpeg;base64,{}", base64::engine::general_purpose::STANDARD.encode(thumbnail)))
+}
+
+fn gps_measure_mode(mode: &str) -> &str {
+    match mode {
+        "2" => "2D",
+        "3" => "3D",
+        _ => mode,
+    }
+}
+
+fn gps_status(status: &str) -> &str {
+    match status {
+        "A" => "active",
+        "V" => "void",
+        _ => status,
+    }
+}
+
+/// Returns the unit of a GPSSpeedRef value.
+fn speed_unit(ref_: &str) -> &str {
+    match ref_ {
+        "K" => "km/h",
+        "M" => "mph",
+        "N" => "knots",
+        _ => ref_,
+    }
+}
+
+/// Returns the width and height of the image in pixels.
+fn get_dimensions(reader: &exif::Reader) -> Option<(f64, f64)> {
+    let width = get_number(reader, exif::Tag::PixelXDimension)
+        .or_else(|| get_number(reader, exif::Tag::ImageWidth))?;
+    let height = get_number(reader, exif::Tag::PixelYDimension)
+        .or_else(|| get_number(reader, exif::Tag::ImageLength))?;
+    Some((width, height))
+}
+
+arg_enum!{
+    /// Properties that can be set on the extracted features.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Property {
+        Filename,
+        Path,
+        Datetime,
+        Make,
+        Model,
+        Direction,
+        GpsDatetime,
+        Iso,
+        Fnumber,
+        Exposure,
+        Focal,
+        Orientation,
+        Dimensions,
+        Speed,
+        FileSize,
+        FileMtime,
+        Dop,
+        Satellites,
+        Dest,
+        Lens,
+        Flash,
+        WhiteBalance,
+        Thumbnail,
+        GpsMode,
+        GpsStatus,
+        Software,
+        Altitude,
+        Metering,
+        Program,
+        Accuracy,
+        Colorspace,
+        BitsPerSample,
+        Sha256,
+        Artist,
+        Copyright,
+        MapUrl,
+        GpsMethod,
+        Distance,
+        Scene,
+        Comment,
+    }
+}
+
+impl Property {
+    /// Returns the key the property is stored under in feature properties.
+    pub fn key(&self) -> String {
+        if *self == Property::GpsMode {
+            return "gps_measure_mode".to_string();
+        }
+
+        let mut key = String::new();
+        for (i, c) in self.to_string().chars().enumerate() {
+            if c.is_uppercase() && i > 0 {
+                key.push('_');
+            }
+            key.extend(c.to_lowercase());
+        }
+        key
+    }
+
+    /// Returns every key the property may set in feature properties.
+    pub fn keys(&self) -> Vec<String> {
+        match self {
+            Property::Direction => vec![self.key(), "direction_ref".to_string()],
+            Property::Speed => vec![self.key(), "speed_unit".to_string()],
+            Property::Dest => vec!["dest_lat".to_string(), "dest_lon".to_string()],
+            Property::Altitude => vec![self.key(), "altitude_ref".to_string()],
+            Property::Flash => vec![self.key(), "flash_mode".to_string()],
+            Property::Dimensions => vec!["width".to_string(), "height".to_string(), "megapixels".to_string()],
+            _ => vec![self.key()],
+        }
+    }
+}
+
+/// Returns every property that can be set on features, except the thumbnail
+/// which inflates the output and the SHA-256 hash which requires reading the
+/// whole file. These have to be requested explicitly.
+pub fn all_properties() -> Vec<Property> {
+    Property::variants().iter()
+        .map(|name| name.parse().unwrap())
+        .filter(|&prop| prop != Property::Thumbnail && prop != Property::Sha256)
+        .collect()
+}
+
+arg_enum!{
+    /// Sources for the id of a feature.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum IdSource {
+        Path,
+        Filename,
+        Hash,
+    }
+}
+
+arg_enum!{
+    /// Units datetimes can be truncated to.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum DatetimeUnit {
+        Minute,
+        Hour,
+        Day,
+    }
+}
+
+impl DatetimeUnit {
+    /// Truncates the datetime to the start of the unit.
+    fn truncate(&self, datetime: &mut exif::DateTime) {
+        datetime.second = 0;
+        if *self == DatetimeUnit::Minute {
+            return;
+        }
+        datetime.minute = 0;
+        if *self == DatetimeUnit::Day {
+            datetime.hour = 0;
+        }
+    }
+}
+
+arg_enum!{
+    /// Output formats the collected features can be written in.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Format {
+        Geojson,
+        Gpx,
+        Kml,
+        Csv,
+        Ndjson,
+        Wkt,
+        Summary,
+        Html,
+        Topojson,
+        Text,
+    }
+}
+
+arg_enum!{
+    /// Order of the coordinates in the text output formats.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum CoordinateOrder {
+        Lonlat,
+        Latlon,
+    }
+}
+
+impl CoordinateOrder {
+    /// Returns the first two values of a position in this order.
+    fn apply<T: Copy>(self, lon: T, lat: T) -> (T, T) {
+        match self {
+            CoordinateOrder::Lonlat => (lon, lat),
+            CoordinateOrder::Latlon => (lat, lon),
+        }
+    }
+}
+
+/// Coordinate reference systems features can be projected to.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Crs {
+    /// WGS 84 longitude and latitude, EPSG:4326.
+    #[default]
+    Wgs84,
+    /// Web Mercator, EPSG:3857.
+    WebMercator,
+}
+
+/// Radius of the sphere used by Web Mercator, in meters.
+const WEB_MERCATOR_RADIUS: f64 = 6_378_137.0;
+
+/// Largest latitude that can be projected to Web Mercator.
+const WEB_MERCATOR_MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+impl Crs {
+    /// Returns the CRS with the given EPSG code, if it is supported.
+    pub fn from_epsg(code: u32) -> Option<Crs> {
+        match code {
+            4326 => Some(Crs::Wgs84),
+            3857 => Some(Crs::WebMercator),
+            _ => None,
+        }
+    }
+
+    pub fn epsg(&self) -> u32 {
+        match self {
+            Crs::Wgs84 => 4326,
+            Crs::WebMercator => 3857,
+        }
+    }
+
+    /// Projects a WGS 84 longitude and latitude to this CRS.
+    pub fn project(&self, longitude: f64, latitude: f64) -> (f64, f64) {
+        match self {
+            Crs::Wgs84 => (longitude, latitude),
+            Crs::WebMercator => {
+                let latitude = latitude.clamp(-WEB_MERCATOR_MAX_LATITUDE, WEB_MERCATOR_MAX_LATITUDE);
+                let x = WEB_MERCATOR_RADIUS * longitude.to_radians();
+                let y = WEB_MERCATOR_RADIUS * (std::f64::consts::FRAC_PI_4 + latitude.to_radians() / 2.0).tan().ln();
+                (x, y)
+            }
+        }
+    }
+
+    /// Returns a GeoJSON 2008 style `crs` member naming this CRS.
+    pub fn to_json(&self) -> JsonValue {
+        let mut properties = Map::new();
+        properties.insert("name".to_string(), format!("urn:ogc:def:crs:EPSG::{}", self.epsg()).into());
+
+        let mut crs = Map::new();
+        crs.insert("type".to_string(), "name".into());
+        crs.insert("properties".to_string(), properties.into());
+        crs.into()
+    }
+}
+
+/// Timezone used for datetimes that have no offset in the EXIF data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timezone {
+    /// A fixed offset from UTC, in minutes.
+    Fixed(i16),
+    /// An IANA timezone, e.g. Europe/Helsinki.
+    Named(chrono_tz::Tz),
+}
+
+impl std::str::FromStr for Timezone {
+    type Err = String;
+
+    /// Parses an offset such as `+02:00`, `-0530` or `Z`, or an IANA
+    /// timezone name.
+    fn from_str(s: &str) -> std::result::Result<Timezone, String> {
+        if s == "Z" || s == "z" {
+            return Ok(Timezone::Fixed(0));
+        }
+
+        if let Some(sign) = s.chars().next().filter(|&c| c == '+' || c == '-') {
+            let rest = &s[1..];
+            // Slicing below assumes one byte per character.
+            if !rest.is_ascii() {
+                return Err(format!("invalid UTC offset: {}", s));
+            }
+            let (hours, minutes) = match rest.len() {
+                2 => (rest, "00"),
+                4 => (&rest[..2], &rest[2..]),
+                5 if rest.as_bytes()[2] == b':' => (&rest[..2], &rest[3..]),
+                _ => return Err(format!("invalid UTC offset: {}", s)),
+            };
+            let valid = hours.bytes().chain(minutes.bytes()).all(|b| b.is_ascii_digit());
+            return match (hours.parse::<i16>(), minutes.parse::<i16>()) {
+                (Ok(hours), Ok(minutes)) if valid && hours <= 14 && minutes < 60 => {
+                    let offset = hours * 60 + minutes;
+                    Ok(Timezone::Fixed(if sign == '-' { -offset } else { offset }))
+                },
+                _ => Err(format!("invalid UTC offset: {}", s)),
+            };
+        }
+
+        s.parse::<chrono_tz::Tz>()
+            .map(Timezone::Named)
+            .map_err(|_| format!("unknown timezone: {}", s))
+    }
+}
+
+impl Timezone {
+    /// Returns the offset from UTC in minutes at a local datetime. Local
+    /// times that occur twice when clocks are turned back use the earlier
+    /// offset, and times skipped when clocks are turned forward have none.
+    fn offset_at(&self, datetime: &exif::DateTime) -> Option<i16> {
+        use chrono::{Offset, TimeZone};
+
+        match self {
+            Timezone::Fixed(offset) => Some(*offset),
+            Timezone::Named(tz) => {
+                let local = naive_datetime(datetime)?;
+                let offset = tz.offset_from_local_datetime(&local).earliest()?;
+                Some((offset.fix().local_minus_utc() / 60) as i16)
+            },
+        }
+    }
+}
+
+/// A GPX track used to locate photos that have no GPS position.
+#[derive(Debug, Clone, Default)]
+pub struct GpxTrack {
+    points: Vec<gpx::TrackPoint>,
+}
+
+impl std::str::FromStr for GpxTrack {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<GpxTrack, String> {
+        let points = gpx::parse_track_points(s)?;
+        if points.is_empty() {
+            return Err("no track points with a time".to_string());
+        }
+        Ok(GpxTrack { points })
+    }
+}
+
+impl GpxTrack {
+    /// Returns the latitude and longitude at a time, in seconds since the
+    /// Unix epoch, interpolated between the closest track points. Times
+    /// outside the track have no position.
+    fn position_at(&self, time: i64) -> Option<(f64, f64)> {
+        let index = match self.points.binary_search_by_key(&time, |point| point.time) {
+            Ok(index) => {
+                let point = &self.points[index];
+                return Some((point.latitude, point.longitude));
+            },
+            Err(index) => index,
+        };
+
+        if index == 0 || index == self.points.len() {
+            return None;
+        }
+
+        let (before, after) = (&self.points[index - 1], &self.points[index]);
+        let t = (time - before.time) as f64 / (after.time - before.time) as f64;
+        Some((before.latitude + t * (after.latitude - before.latitude),
+            before.longitude + t * (after.longitude - before.longitude)))
+    }
+}
+
+/// Contexts of the IFDs tags are looked up from by name or number.
+const TAG_CONTEXTS: [exif::Context; 4] = [exif::Context::Tiff, exif::Context::Exif, exif::Context::Gps,
+    exif::Context::Interop];
+
+/// Parses an EXIF tag given by its name, e.g. `DateTimeOriginal`, or by its
+/// number, e.g. `0x9003`. A number that is not a known tag is accepted and
+/// matches a field with that number in any IFD of the primary image.
+pub fn parse_tag(s: &str) -> std::result::Result<exif::Tag, String> {
+    let number = if s.starts_with("0x") || s.starts_with("0X") {
+        Some(u16::from_str_radix(&s[2..], 16).map_err(|_| format!("invalid tag number: {}", s))?)
+    } else {
+        s.parse::<u16>().ok()
+    };
+
+    let known = TAG_CONTEXTS.iter()
+        .flat_map(|&context| match number {
+            Some(number) => number..=number,
+            None => 0..=u16::MAX,
+        }.map(move |number| exif::Tag(context, number)))
+        .filter(|tag| tag.description().is_some())
+        .find(|tag| number.is_some() || tag.to_string() == s);
+
+    match (known, number) {
+        (Some(tag), _) => Ok(tag),
+        (None, Some(number)) => Ok(exif::Tag(exif::Context::Tiff, number)),
+        (None, None) => Err(format!("unknown tag: {}", s)),
+    }
+}
+
+/// Returns the property key of a tag: its name, or its number in hex if it
+/// is not a known tag.
+pub fn tag_key(tag: exif::Tag) -> String {
+    match tag.description() {
+        Some(_) => tag.to_string(),
+        None => format!("0x{:04x}", tag.number()),
+    }
+}
+
+/// Returns the field of a tag of the primary image. Tags that are not known
+/// are matched by their number only.
+fn find_field(reader: &exif::Reader, tag: exif::Tag) -> Option<&exif::Field<'_>> {
+    if tag.description().is_some() {
+        return reader.get_field(tag, false);
+    }
+    reader.fields().iter().find(|field| !field.thumbnail && field.tag.number() == tag.number())
+}
+
+/// Handler of warnings about photos whose features could still be built. It
+/// is called with the path of the photo and the warning.
+#[derive(Clone)]
+pub struct WarningHandler(Arc<WarningFn>);
+
+type WarningFn = dyn Fn(&Path, &str) + Send + Sync;
+
+impl WarningHandler {
+    pub fn new<F>(handler: F) -> WarningHandler
+        where F: Fn(&Path, &str) + Send + Sync + 'static
+    {
+        WarningHandler(Arc::new(handler))
+    }
+}
+
+impl std::fmt::Debug for WarningHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("WarningHandler")
+    }
+}
+
+/// Options for building features.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Number of decimal places coordinates are rounded to. Coordinates are
+    /// kept at full precision if this is `None` or above `MAX_PRECISION`.
+    pub precision: Option<u32>,
+    /// Keep the datetime property in the original EXIF format instead of
+    /// converting it to RFC 3339.
+    pub raw_datetime: bool,
+    /// What the id of the feature is derived from. Features have no id if
+    /// this is `None`.
+    pub id: Option<IdSource>,
+    /// Directory the path property is made relative to. It must be a
+    /// canonical path. Paths are absolute if this is `None`.
+    pub relative_to: Option<PathBuf>,
+    /// Coordinate reference system of the feature coordinates.
+    pub crs: Crs,
+    /// Timezone of datetimes that have no offset in the EXIF data. Such
+    /// datetimes are left without an offset if this is `None`.
+    pub timezone: Option<Timezone>,
+    /// Unit the datetime property is truncated to. It is kept at full
+    /// resolution if this is `None`.
+    pub round_datetime: Option<DatetimeUnit>,
+    /// Additional EXIF tags whose values are set as properties, keyed by
+    /// `tag_key`.
+    pub tags: Vec<exif::Tag>,
+    /// Track that photos without a GPS position are located on by their
+    /// datetime.
+    pub gpx: Option<Arc<GpxTrack>>,
+    /// Handler of warnings about the photos. Warnings are ignored if this is
+    /// `None`.
+    pub warning_handler: Option<WarningHandler>,
+}
+
+impl Options {
+    fn warn(&self, filename: &Path, warning: &str) {
+        if let Some(WarningHandler(ref handler)) = self.warning_handler {
+            handler(filename, warning);
+        }
+    }
+}
+
+/// Length of the ids derived from file contents, in hex digits.
+const HASH_ID_LENGTH: usize = 12;
+
+/// Returns the hex digest of the file contents. The file is streamed through
+/// the hasher.
+fn hash_file<D: Digest + Write>(filename: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(filename)?;
+    let mut hasher = D::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn get_id(filename: &Path, source: IdSource) -> Result<geojson::feature::Id> {
+    let id = match source {
+        IdSource::Path => filename.canonicalize()?.to_string_lossy().into_owned(),
+        IdSource::Filename => filename.file_name().unwrap().to_string_lossy().into_owned(),
+        IdSource::Hash => {
+            let mut hash = hash_file::<Sha1>(filename)?;
+            hash.truncate(HASH_ID_LENGTH);
+            hash
+        },
+    };
+    Ok(geojson::feature::Id::String(id))
+}
+
+/// The largest number of decimal places coordinates can be rounded to. An
+/// `f64` has no more significant digits than this.
+pub const MAX_PRECISION: u32 = 15;
+
+fn round_to(value: f64, precision: u32) -> f64 {
+    if precision > MAX_PRECISION {
+        return value;
+    }
+    let factor = 10f64.powi(precision as i32);
+    (value * factor).round() / factor
+}
+
+/// Reads the GPS location of a photo and builds a GeoJSON `Feature` with the
+/// requested properties.
+///
+/// ```
+/// extern crate geojson;
+/// extern crate plag;
+///
+/// use std::path::Path;
+///
+/// use geojson::{Geometry, Value};
+/// use plag::Property;
+///
+/// let feature = plag::get_feature(Path::new("tests/data/photo.jpg"), &[Property::Filename]).unwrap();
+///
+/// match feature.geometry {
+///     Some(Geometry { value: Value::Point(_), .. }) => {},
+///     _ => panic!("expected a point"),
+/// }
+/// ```
+pub fn get_feature(filename: &Path, properties: &[Property]) -> Result<Feature> {
+    get_feature_with_options(filename, properties, &Options::default())
+}
+
+/// Like `get_feature`, but allows tuning how the feature is built.
+pub fn get_feature_with_options(filename: &Path, properties: &[Property], options: &Options) -> Result<Feature> {
+    let (reader, xmp) = read_metadata(filename)?;
+
+    let (reader, location) = match reader {
+        Ok(reader) => {
+            let location = get_location(&reader, xmp.as_deref());
+            (reader, location)
+        },
+        // Photos without EXIF data may still have a position in their XMP
+        // packet.
+        Err(error) => {
+            let location = xmp.as_deref().map(get_xmp_location).transpose()?.flatten().ok_or(error)?;
+            (empty_exif()?, Ok(location))
+        },
+    };
+
+    let round = |value: f64| match options.precision {
+        Some(precision) => round_to(value, precision),
+        None => value,
+    };
+
+    let (latitude, longitude) = match (location, &options.gpx) {
+        (Err(error), Some(track)) if error.is_missing_gps() => get_timestamp(&reader, options.timezone)
+            .and_then(|time| track.position_at(time))
+            .ok_or(error)?,
+        (location, _) => location?,
+    };
+    let (x, y) = options.crs.project(longitude, latitude);
+    let (x, y) = (round(x), round(y));
+    let point: geo_types::Point<f64> = (x, y).into();
+
+    let altitude = get_altitude(&reader);
+
+    let value = match altitude.map(|(altitude, _)| round(altitude)) {
+        Some(altitude) => Value::Point(vec![x, y, altitude]),
+        None => Value::from(&point),
+    };
+
+    let mut props = Map::new();
+
+    for prop in properties {
+        let value = match prop {
+            Property::Filename => Some(filename.file_name().unwrap().to_string_lossy().into()),
+            Property::Path => {
+                let path = filename.canonicalize()?;
+                let path = match options.relative_to {
+                    Some(ref base) => match path.strip_prefix(base) {
+                        Ok(relative) => relative,
+                        Err(_) => {
+                            options.warn(filename, &format!("not under {}, using absolute path", base.display()));
+                            &path
+                        }
+                    },
+                    None => &path,
+                };
+                Some(path.to_string_lossy().into())
+            },
+            // Photos without a datetime are left without the property, like
+            // with the other optional properties.
+            Property::Datetime => match get_string(&reader, exif::Tag::DateTimeOriginal) {
+                Ok(data) if options.raw_datetime => Some(data.into()),
+                Ok(data) => match get_datetime(&reader) {
+                    Ok(mut datetime) => {
+                        if datetime.offset.is_none() {
+                            datetime.offset = options.timezone.and_then(|tz| tz.offset_at(&datetime));
+                        }
+                        if let Some(unit) = options.round_datetime {
+                            unit.truncate(&mut datetime);
+                        }
+                        Some(format_datetime(&datetime).into())
+                    },
+                    Err(error) => {
+                        options.warn(filename, &format!("using raw datetime: {}", error));
+                        Some(data.into())
+                    }
+                },
+                Err(_) => None,
+            },
+            Property::Make => get_string(&reader, exif::Tag::Make).ok().map(JsonValue::from),
+            Property::Model => get_string(&reader, exif::Tag::Model).ok().map(JsonValue::from),
+            Property::Software => get_string(&reader, exif::Tag::Software).ok().map(JsonValue::from),
+            Property::MapUrl => Some(format!("https://www.openstreetmap.org/?mlat={}&mlon={}",
+                round(latitude), round(longitude)).into()),
+            Property::Artist => get_string(&reader, exif::Tag::Artist).ok().map(JsonValue::from),
+            Property::Copyright => get_string(&reader, exif::Tag::Copyright).ok().map(JsonValue::from),
+            Property::Direction => {
+                let direction = get_rational(&reader, exif::Tag::GPSImgDirection).ok();
+                if direction.is_some() {
+                    if let Ok(ref_) = get_string(&reader, exif::Tag::GPSImgDirectionRef) {
+                        props.insert("direction_ref".to_string(), ref_.into());
+                    }
+                }
+                direction.map(JsonValue::from)
+            },
+            Property::GpsDatetime => get_gps_datetime(&reader).map(JsonValue::from),
+            Property::Iso => get_number(&reader, exif::Tag::PhotographicSensitivity)
+                .or_else(|| get_number(&reader, exif::Tag::ISOSpeed))
+                .map(number_value),
+            Property::Fnumber => get_number(&reader, exif::Tag::FNumber).map(number_value),
+            Property::Exposure => get_number(&reader, exif::Tag::ExposureTime).map(number_value),
+            Property::Distance => get_subject_distance(&reader),
+            Property::Focal => get_number(&reader, exif::Tag::FocalLength).map(number_value),
+            Property::Orientation => get_short(&reader, exif::Tag::Orientation).ok().map(JsonValue::from),
+            Property::Speed => {
+                let speed = get_rational(&reader, exif::Tag::GPSSpeed).ok();
+                if speed.is_some() {
+                    if let Ok(ref_) = get_string(&reader, exif::Tag::GPSSpeedRef) {
+                        props.insert("speed_unit".to_string(), speed_unit(&ref_).into());
+                    }
+                }
+                speed.map(number_value)
+            },
+            Property::Dop => get_rational(&reader, exif::Tag::GPSDOP).ok().map(number_value),
+            Property::Accuracy => get_rational(&reader, exif::Tag::GPSHPositioningError).ok().map(number_value),
+            Property::Satellites => get_string(&reader, exif::Tag::GPSSatellites).ok().map(|satellites| {
+                let satellites = satellites.trim();
+                match satellites.parse::<u64>() {
+                    Ok(count) => count.into(),
+                    Err(_) => satellites.into(),
+                }
+            }),
+            Property::FileSize => std::fs::metadata(filename).ok().map(|metadata| metadata.len().into()),
+            Property::FileMtime => std::fs::metadata(filename)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .map(|time| format_system_time(time).into()),
+            Property::Flash => get_short(&reader, exif::Tag::Flash).ok().map(|flash| {
+                props.insert("flash_mode".to_string(), flash_mode(flash).into());
+                JsonValue::from(flash & 0x1 == 1)
+            }),
+            Property::WhiteBalance => get_short(&reader, exif::Tag::WhiteBalance).ok()
+                .and_then(white_balance)
+                .map(JsonValue::from),
+            Property::GpsMode => get_string(&reader, exif::Tag::GPSMeasureMode).ok()
+                .map(|mode| gps_measure_mode(mode.trim()).into()),
+            Property::GpsStatus => get_string(&reader, exif::Tag::GPSStatus).ok()
+                .map(|status| gps_status(status.trim()).into()),
+            Property::GpsMethod => get_encoded_string(&reader, exif::Tag::GPSProcessingMethod).map(JsonValue::from),
+            Property::Comment => get_encoded_string(&reader, exif::Tag::UserComment).map(JsonValue::from),
+            Property::Thumbnail => get_thumbnail(&reader).map(JsonValue::from),
+            Property::Sha256 => Some(hash_file::<Sha256>(filename)?.into()),
+            Property::Metering => get_short(&reader, exif::Tag::MeteringMode).ok()
+                .map(|value| decode_short(value, metering_mode)),
+            Property::Program => get_short(&reader, exif::Tag::ExposureProgram).ok()
+                .map(|value| decode_short(value, exposure_program)),
+            Property::Scene => get_short(&reader, exif::Tag::SceneCaptureType).ok()
+                .map(|value| decode_short(value, scene_capture_type)),
+            Property::Colorspace => get_short(&reader, exif::Tag::ColorSpace).ok()
+                .map(|value| decode_short(value, color_space)),
+            Property::BitsPerSample => get_bits_per_sample(&reader),
+            Property::Lens => get_lens(&reader).map(JsonValue::from),
+            Property::Altitude => altitude.map(|(altitude, below_sea_level)| {
+                let ref_ = if below_sea_level { "below" } else { "above" };
+                props.insert("altitude_ref".to_string(), ref_.into());
+                number_value(round(altitude))
+            }),
+            Property::Dest => {
+                let lat = get_coordinate(&reader, exif::Tag::GPSDestLatitude, exif::Tag::GPSDestLatitudeRef, "south");
+                let lon = get_coordinate(&reader, exif::Tag::GPSDestLongitude, exif::Tag::GPSDestLongitudeRef, "west");
+                if let (Ok(lat), Ok(lon)) = (lat, lon) {
+                    props.insert("dest_lat".to_string(), round(lat).into());
+                    props.insert("dest_lon".to_string(), round(lon).into());
+                }
+                None
+            },
+            Property::Dimensions => {
+                if let Some((width, height)) = get_dimensions(&reader) {
+                    props.insert("width".to_string(), number_value(width));
+                    props.insert("height".to_string(), number_value(height));
+                    props.insert("megapixels".to_string(), number_value(round_to(width * height / 1e6, 1)));
+                }
+                None
+            },
+        };
+        if let Some(value) = value {
+            props.insert(prop.key(), value);
+        }
+    }
+
+    for &tag in &options.tags {
+        if let Some(field) = find_field(&reader, tag) {
+            props.insert(tag_key(tag), field.value.display_as(field.tag).to_string().into());
+        }
+    }
+
+    let id = match options.id {
+        Some(source) => Some(get_id(filename, source)?),
+        None => None,
+    };
+
+    Ok(Feature {
+        bbox: None,
+        geometry: Some(Geometry::new(value)),
+        id,
+        properties: Some(props),
+        foreign_members: None,
+    })
+}
+
+fn collect_dir(dir: &Path, recursive: bool, visited: &mut HashSet<PathBuf>, files: &mut Vec<PathBuf>,
+    errors: &mut Vec<(PathBuf, Error)>)
+{
+    // Directories are tracked by their canonical path so that symlink loops
+    // are walked only once.
+    match dir.canonicalize() {
+        Ok(canonical) => {
+            if !visited.insert(canonical) {
+                return;
+            }
+        },
+        Err(error) => {
+            errors.push((dir.to_path_buf(), Error::IoError(error)));
+            return;
+        }
+    }
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            errors.push((dir.to_path_buf(), Error::IoError(error)));
+            return;
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            if recursive {
+                collect_dir(&path, recursive, visited, files, errors);
+            }
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+}
+
+/// Expands the directories in `paths` into the regular files they contain.
+/// Returns the files, and the directories that could not be read along with
+/// the reason.
+pub fn collect_files(paths: &[PathBuf], recursive: bool) -> (Vec<PathBuf>, Vec<(PathBuf, Error)>) {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    let mut visited = HashSet::new();
+
+    for path in paths {
+        if path.is_dir() {
+            collect_dir(path, recursive, &mut visited, &mut files, &mut errors);
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    (files, errors)
+}
+
+/// Extracts features from the given files in parallel. Returns the features
+/// in the order of the files, and the files that could not be processed
+/// along with the reason.
+pub fn process_files(paths: &[PathBuf], properties: &[Property]) -> (Vec<Feature>, Vec<(PathBuf, Error)>) {
+    process_files_with_options(paths, properties, &Options::default())
+}
+
+/// Like `process_files`, but allows tuning how the features are built.
+pub fn process_files_with_options(paths: &[PathBuf], properties: &[Property], options: &Options)
+    -> (Vec<Feature>, Vec<(PathBuf, Error)>)
+{
+    let results = process_each_file(paths, properties, options);
+
+    let mut features = Vec::new();
+    let mut errors = Vec::new();
+
+    for (path, result) in paths.iter().zip(results) {
+        match result {
+            Ok(feature) => features.push(feature),
+            Err(error) => errors.push((path.clone(), error)),
+        }
+    }
+
+    (features, errors)
+}
+
+/// Extracts features from the given files in parallel. Returns the result of
+/// every file in the order of the files.
+pub fn process_each_file(paths: &[PathBuf], properties: &[Property], options: &Options) -> Vec<Result<Feature>> {
+    paths.par_iter()
+        .map(|path| get_feature_with_options(path, properties, options))
+        .collect()
+}
+
+fn get_position(feature: &Feature) -> Option<&Vec<f64>> {
+    match feature.geometry {
+        Some(Geometry { value: Value::Point(ref position), .. }) => Some(position),
+        _ => None,
+    }
+}
+
+fn get_property<'a>(feature: &'a Feature, property: &Property) -> Option<&'a JsonValue> {
+    feature.properties.as_ref().and_then(|props| props.get(&property.key()))
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_csv(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Collapses features that share the same longitude and latitude into the
+/// first of them. The coordinates are compared after rounding them to
+/// `precision` decimal places, if given. The number of collapsed features is
+/// stored in the `count` property.
+pub fn dedup_features(features: Vec<Feature>, precision: Option<u32>) -> Vec<Feature> {
+    let mut deduped: Vec<Feature> = Vec::new();
+    let mut counts: Vec<u64> = Vec::new();
+    let mut seen = HashMap::new();
+
+    for feature in features {
+        let key = get_position(&feature).map(|position| {
+            let (lon, lat) = match precision {
+                Some(precision) => (round_to(position[0], precision), round_to(position[1], precision)),
+                None => (position[0], position[1]),
+            };
+            (lon.to_bits(), lat.to_bits())
+        });
+
+        if let Some(&index) = key.and_then(|key| seen.get(&key)) {
+            counts[index] += 1;
+            continue;
+        }
+
+        if let Some(key) = key {
+            seen.insert(key, deduped.len());
+        }
+        deduped.push(feature);
+        counts.push(1);
+    }
+
+    for (feature, count) in deduped.iter_mut().zip(counts) {
+        feature.properties.get_or_insert_with(Map::new).insert("count".to_string(), count.into());
+    }
+
+    deduped
+}
+
+/// Approximate length of a degree of latitude in meters.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// A cluster of features being built, with the sums of the coordinates of
+/// its photos.
+struct Cluster {
+    feature: Feature,
+    lon_sum: f64,
+    lat_sum: f64,
+    /// Sum of the altitudes, or `None` if some photo has no altitude.
+    altitude_sum: Option<f64>,
+    count: u64,
+}
+
+/// Groups features into clusters on a grid of `radius` meter cells. Each
+/// cluster is placed at the centroid of its photos, rounded to `precision`
+/// decimal places, if given. The altitude is averaged if every photo has one.
+/// The number of photos in the cluster is stored in the `count` property,
+/// adding up the counts of features that already have one. Clusters of more
+/// than one photo have no id and no other properties, while a single photo
+/// keeps its own.
+pub fn cluster_features(features: Vec<Feature>, radius: f64, precision: Option<u32>) -> Vec<Feature> {
+    let mut clusters: Vec<Cluster> = Vec::new();
+    let mut cells: HashMap<(i64, i64), usize> = HashMap::new();
+
+    for feature in features {
+        let (lon, lat, altitude) = match get_position(&feature) {
+            Some(position) => (position[0], position[1], position.get(2).cloned()),
+            None => continue,
+        };
+
+        let count = feature.properties.as_ref()
+            .and_then(|props| props.get("count"))
+            .and_then(|count| count.as_u64())
+            .unwrap_or(1);
+        let weight = count as f64;
+
+        let y = lat * METERS_PER_DEGREE;
+        let x = lon * METERS_PER_DEGREE * lat.to_radians().cos();
+        let cell = ((x / radius).floor() as i64, (y / radius).floor() as i64);
+
+        match cells.get(&cell) {
+            Some(&index) => {
+                let cluster = &mut clusters[index];
+                cluster.lon_sum += lon * weight;
+                cluster.lat_sum += lat * weight;
+                cluster.altitude_sum = match (cluster.altitude_sum, altitude) {
+                    (Some(sum), Some(altitude)) => Some(sum + altitude * weight),
+                    _ => None,
+                };
+                cluster.count += count;
+            },
+            None => {
+                cells.insert(cell, clusters.len());
+                clusters.push(Cluster {
+                    feature,
+                    lon_sum: lon * weight,
+                    lat_sum: lat * weight,
+                    altitude_sum: altitude.map(|altitude| altitude * weight),
+                    count,
+                });
+            },
+        }
+    }
+
+    let round = |value: f64| match precision {
+        Some(precision) => round_to(value, precision),
+        None => value,
+    };
+
+    clusters.into_iter()
+        .map(|cluster| {
+            let mut feature = cluster.feature;
+            let count = cluster.count as f64;
+
+            let mut position = vec![round(cluster.lon_sum / count), round(cluster.lat_sum / count)];
+            if let Some(altitude_sum) = cluster.altitude_sum {
+                position.push(round(altitude_sum / count));
+            }
+            feature.geometry = Some(Geometry::new(Value::Point(position)));
+
+            // The properties of the first photo don't describe the others.
+            if cluster.count > 1 {
+                feature.id = None;
+                feature.properties = Some(Map::new());
+            }
+            feature.properties.get_or_insert_with(Map::new).insert("count".to_string(), cluster.count.into());
+
+            feature
+        })
+        .collect()
+}
+
+/// Removes a property from a feature.
+pub fn remove_property(feature: &mut Feature, property: &Property) {
+    if let Some(ref mut props) = feature.properties {
+        for key in property.keys() {
+            props.remove(&key);
+        }
+    }
+}
+
+/// Renames properties of a feature. Each pair maps an existing key to a new
+/// one.
+pub fn rename_properties(feature: &mut Feature, renames: &[(String, String)]) {
+    if let Some(ref mut props) = feature.properties {
+        for (from, to) in renames {
+            if let Some(value) = props.remove(from) {
+                props.insert(to.clone(), value);
+            }
+        }
+    }
+}
+
+/// Returns true if the GPS status property of the feature says the
+/// measurement is void.
+pub fn is_void(feature: &Feature) -> bool {
+    get_property(feature, &Property::GpsStatus).and_then(|v| v.as_str()) == Some("void")
+}
+
+/// Returns the accuracy property of the feature, the GPS horizontal
+/// positioning error in meters.
+pub fn get_accuracy(feature: &Feature) -> Option<f64> {
+    get_property(feature, &Property::Accuracy).and_then(|v| v.as_f64())
+}
+
+/// Returns the date of a feature as `YYYY-MM-DD`, read from its datetime
+/// property.
+pub fn get_date(feature: &Feature) -> Option<String> {
+    let datetime = get_property(feature, &Property::Datetime)?.as_str()?;
+    // The raw EXIF format uses colons as the date separator.
+    let date = datetime.get(..10)?.replace(':', "-");
+    let valid = date.bytes().enumerate()
+        .all(|(i, b)| if i == 4 || i == 7 { b == b'-' } else { b.is_ascii_digit() });
+    if valid {
+        Some(date)
+    } else {
+        None
+    }
+}
+
+/// Returns true if the location of the feature is within the bounding box
+/// `[min_lon, min_lat, max_lon, max_lat]`.
+pub fn is_within(feature: &Feature, bbox: &[f64; 4]) -> bool {
+    match get_position(feature) {
+        Some(position) => {
+            position[0] >= bbox[0] && position[0] <= bbox[2] &&
+                position[1] >= bbox[1] && position[1] <= bbox[3]
+        },
+        None => false,
+    }
+}
+
+/// Returns the longitude and latitude of the feature if they are outside
+/// the valid WGS 84 ranges, [-180, 180] and [-90, 90].
+pub fn out_of_range_position(feature: &Feature) -> Option<(f64, f64)> {
+    let position = get_position(feature)?;
+    let (lon, lat) = (position[0], position[1]);
+    if (-180.0..=180.0).contains(&lon) && (-90.0..=90.0).contains(&lat) {
+        None
+    } else {
+        Some((lon, lat))
+    }
+}
+
+/// Appends features to existing ones. Features whose id is already used by
+/// one of the existing features are skipped.
+pub fn merge_features(mut existing: Vec<Feature>, features: Vec<Feature>) -> Vec<Feature> {
+    let ids: Vec<_> = existing.iter().filter_map(|feature| feature.id.clone()).collect();
+
+    existing.extend(features.into_iter().filter(|feature| match feature.id {
+        Some(ref id) => !ids.contains(id),
+        None => true,
+    }));
+
+    existing
+}
+
+/// Computes the bounding box of the features as `[min_lon, min_lat, max_lon,
+/// max_lat]`. If any of the features has an altitude, the six element form
+/// with the altitude range is returned instead.
+pub fn compute_bbox(features: &[Feature]) -> Option<Vec<f64>> {
+    let mut min = [f64::INFINITY; 3];
+    let mut max = [f64::NEG_INFINITY; 3];
+    let mut found = false;
+    let mut has_altitude = false;
+
+    for position in features.iter().filter_map(get_position) {
+        found = true;
+        has_altitude |= position.len() > 2;
+        for (i, c) in position.iter().take(3).enumerate() {
+            min[i] = min[i].min(*c);
+            max[i] = max[i].max(*c);
+        }
+    }
+
+    if !found {
+        return None;
+    }
+
+    if has_altitude {
+        Some(vec![min[0], min[1], min[2], max[0], max[1], max[2]])
+    } else {
+        Some(vec![min[0], min[1], max[0], max[1]])
+    }
+}
+
+/// Writes the GeoJSON indented by `indent` spaces per level, or compactly on
+/// one line if `indent` is `None`.
+fn write_json<W: Write>(geojson: &GeoJson, indent: Option<usize>, writer: W) -> Result<()> {
+    match indent {
+        Some(indent) => {
+            let indent = vec![b' '; indent];
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(&indent);
+            let mut serializer = serde_json::Serializer::with_formatter(writer, formatter);
+            serde::Serialize::serialize(geojson, &mut serializer).map_err(std::io::Error::from)?;
+        },
+        None => serde_json::to_writer(writer, geojson).map_err(std::io::Error::from)?,
+    }
+
+    Ok(())
+}
+
+/// Writes the features as a FeatureCollection. The foreign members are
+/// added to the collection object, if given.
+pub fn write_geojson<W: Write>(features: Vec<Feature>, foreign_members: Option<Map<String, JsonValue>>, indent: Option<usize>,
+    writer: W) -> Result<()>
+{
+    let collection = FeatureCollection {
+        bbox: compute_bbox(&features),
+        features,
+        foreign_members,
+    };
+
+    write_json(&GeoJson::from(collection), indent, writer)
+}
+
+/// Writes an HTML page that shows the features on a map. The page embeds
+/// Leaflet and the features, so only the map tiles are loaded from
+/// OpenStreetMap. The properties of a feature are shown in a popup when its
+/// marker is clicked.
+pub fn write_html<W: Write>(features: Vec<Feature>, mut writer: W) -> Result<()> {
+    let collection = FeatureCollection {
+        bbox: compute_bbox(&features),
+        features,
+        foreign_members: None,
+    };
+
+    // "<" can only appear inside strings, where it is escaped so that the
+    // data can't end the script element or open a comment in it.
+    let geojson = serde_json::to_string(&GeoJson::from(collection))
+        .map_err(std::io::Error::from)?
+        .replace('<', "\\u003c");
+
+    // The features are inserted last so that they are never searched for
+    // the other placeholders.
+    let page = include_str!("map.html")
+        .replace("{{LEAFLET_CSS}}", include_str!("leaflet/leaflet.css"))
+        .replace("{{LEAFLET_JS}}", include_str!("leaflet/leaflet.js"))
+        .replace("{{GEOJSON}}", &geojson);
+
+    writer.write_all(page.as_bytes())?;
+
+    Ok(())
+}
+
+/// Returns the datetime property of a feature in seconds since the Unix
+/// epoch. Datetimes without an offset, including raw EXIF datetimes, are
+/// taken to be in UTC.
+fn get_feature_timestamp(feature: &Feature) -> Option<i64> {
+    let datetime = get_property(feature, &Property::Datetime)?.as_str()?.trim();
+
+    if let Ok(datetime) = chrono::DateTime::parse_from_rfc3339(datetime) {
+        return Some(datetime.timestamp());
+    }
+
+    ["%Y-%m-%dT%H:%M:%S", "%Y:%m:%d %H:%M:%S"].iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(datetime, format).ok())
+        .map(|datetime| datetime.and_utc().timestamp())
+}
+
+/// Sorts the features chronologically by their datetime property. Features
+/// without a datetime that can be read are placed last.
+pub fn sort_by_datetime(features: &mut [Feature]) {
+    features.sort_by_key(|feature| {
+        let timestamp = get_feature_timestamp(feature);
+        (timestamp.is_none(), timestamp)
+    });
+}
+
+/// Sorts the features by their datetime property and connects them into a
+/// single `LineString` feature. Features without a datetime are placed last.
+pub fn build_track(mut features: Vec<Feature>) -> Feature {
+    sort_by_datetime(&mut features);
+
+    let coordinates = features.iter().filter_map(get_position).cloned().collect();
+
+    Feature {
+        bbox: None,
+        geometry: Some(Geometry::new(Value::LineString(coordinates))),
+        id: None,
+        properties: Some(Map::new()),
+        foreign_members: None,
+    }
+}
+
+pub fn write_track<W: Write>(features: Vec<Feature>, indent: Option<usize>, writer: W) -> Result<()> {
+    write_json(&GeoJson::from(build_track(features)), indent, writer)
+}
+
+/// Builds a single feature with a MultiPoint geometry of the positions of the
+/// features. The properties of the features are dropped.
+pub fn build_multipoint(features: &[Feature]) -> Feature {
+    let coordinates = features.iter().filter_map(get_position).cloned().collect();
+
+    Feature {
+        bbox: None,
+        geometry: Some(Geometry::new(Value::MultiPoint(coordinates))),
+        id: None,
+        properties: Some(Map::new()),
+        foreign_members: None,
+    }
+}
+
+/// Writes a single feature as the top-level GeoJSON object.
+pub fn write_feature<W: Write>(feature: Feature, indent: Option<usize>, writer: W) -> Result<()> {
+    write_json(&GeoJson::from(feature), indent, writer)
+}
+
+pub fn write_multipoint<W: Write>(features: &[Feature], indent: Option<usize>, writer: W) -> Result<()> {
+    write_json(&GeoJson::from(build_multipoint(features)), indent, writer)
+}
+
+/// Number of distinct values quantized TopoJSON coordinates can have on each
+/// axis.
+const TOPOJSON_QUANTIZATION: f64 = 1e5;
+
+/// Writes the features as a quantized TopoJSON topology with a single
+/// GeometryCollection object named `photos`.
+pub fn write_topojson<W: Write>(features: &[Feature], mut writer: W) -> Result<()> {
+    let mut topology = Map::new();
+    topology.insert("type".to_string(), "Topology".into());
+
+    let bbox = compute_bbox(features);
+
+    // The translation and scale used to quantize the coordinates.
+    let transform = bbox.as_ref().map(|bbox| {
+        let (x0, y0) = (bbox[0], bbox[1]);
+        let (x1, y1) = (bbox[bbox.len() / 2], bbox[bbox.len() / 2 + 1]);
+        let scale = |min: f64, max: f64| if max > min { (max - min) / (TOPOJSON_QUANTIZATION - 1.0) } else { 1.0 };
+        ([x0, y0], [scale(x0, x1), scale(y0, y1)])
+    });
+
+    if let (Some(bbox), Some((translate, scale))) = (bbox, transform) {
+        let mut object = Map::new();
+        object.insert("scale".to_string(), scale.to_vec().into());
+        object.insert("translate".to_string(), translate.to_vec().into());
+        topology.insert("transform".to_string(), object.into());
+        topology.insert("bbox".to_string(), bbox.into());
+    }
+
+    // Quantizes the longitude and latitude of a position. Any altitude is
+    // kept as is.
+    let quantize = |position: &[f64]| -> Vec<JsonValue> {
+        let mut coordinates: Vec<JsonValue> = match transform {
+            Some((translate, scale)) => (0..2)
+                .map(|i| JsonValue::from(((position[i] - translate[i]) / scale[i]).round() as i64))
+                .collect(),
+            None => Vec::new(),
+        };
+        coordinates.extend(position[2..].iter().map(|&c| JsonValue::from(c)));
+        coordinates
+    };
+
+    let geometries: Vec<JsonValue> = features.iter()
+        .filter_map(|feature| {
+            let position = get_position(feature)?;
+            let mut geometry = Map::new();
+            geometry.insert("type".to_string(), "Point".into());
+            geometry.insert("coordinates".to_string(), quantize(position).into());
+            if let Some(ref id) = feature.id {
+                geometry.insert("id".to_string(), serde_json::to_value(id).ok()?);
+            }
+            if let Some(ref props) = feature.properties {
+                if !props.is_empty() {
+                    geometry.insert("properties".to_string(), props.clone().into());
+                }
+            }
+            Some(geometry.into())
+        })
+        .collect();
+
+    let mut collection = Map::new();
+    collection.insert("type".to_string(), "GeometryCollection".into());
+    collection.insert("geometries".to_string(), geometries.into());
+
+    let mut objects = Map::new();
+    objects.insert("photos".to_string(), collection.into());
+    topology.insert("objects".to_string(), objects.into());
+    topology.insert("arcs".to_string(), JsonValue::Array(Vec::new()));
+
+    serde_json::to_writer(&mut writer, &topology).map_err(std::io::Error::from)?;
+
+    Ok(())
+}
+
+/// Writes the location of each feature as a WKT point on its own line.
+pub fn write_wkt<W: Write>(features: &[Feature], order: CoordinateOrder, mut writer: W) -> Result<()> {
+    for position in features.iter().filter_map(get_position) {
+        let (x, y) = order.apply(position[0], position[1]);
+        match position.get(2) {
+            Some(altitude) => writeln!(writer, "POINT Z({} {} {})", x, y, altitude)?,
+            None => writeln!(writer, "POINT({} {})", x, y)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the coordinates of each feature on its own line, separated by a
+/// comma, e.g. `60.17, 24.94`. Properties are not written.
+pub fn write_text<W: Write>(features: &[Feature], order: CoordinateOrder, mut writer: W) -> Result<()> {
+    for position in features.iter().filter_map(get_position) {
+        let (x, y) = order.apply(position[0], position[1]);
+        writeln!(writer, "{}, {}", x, y)?;
+    }
+
+    Ok(())
+}
+
+/// Writes each feature as a compact GeoJSON object on its own line.
+pub fn write_ndjson<W: Write>(features: &[Feature], mut writer: W) -> Result<()> {
+    for feature in features {
+        serde_json::to_writer(&mut writer, feature).map_err(std::io::Error::from)?;
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a line describing the outcome of processing a file: its position
+/// and `"ok"` as the status, or the error message as the status if it failed.
+pub fn write_summary<W: Write>(path: &Path, result: &Result<Feature>, mut writer: W) -> Result<()> {
+    let mut record = Map::new();
+    record.insert("file".to_string(), path.to_string_lossy().into());
+
+    let position = result.as_ref().ok().and_then(get_position);
+    record.insert("lon".to_string(), position.map_or(JsonValue::Null, |position| position[0].into()));
+    record.insert("lat".to_string(), position.map_or(JsonValue::Null, |position| position[1].into()));
+
+    let status = match result {
+        Ok(_) => "ok".to_string(),
+        Err(error) => error.to_string(),
+    };
+    record.insert("status".to_string(), status.into());
+
+    serde_json::to_writer(&mut writer, &record).map_err(std::io::Error::from)?;
+    writeln!(writer)?;
+
+    Ok(())
+}
+
+pub fn write_gpx<W: Write>(features: &[Feature], mut writer: W) -> Result<()> {
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<gpx version="1.1" creator="plag" xmlns="http://www.topografix.com/GPX/1/1">"#)?;
+
+    for feature in features {
+        let position = match get_position(feature) {
+            Some(position) => position,
+            None => continue,
+        };
+
+        writeln!(writer, r#"  <wpt lat="{}" lon="{}">"#, position[1], position[0])?;
+
+        if let Some(altitude) = position.get(2) {
+            writeln!(writer, "    <ele>{}</ele>", altitude)?;
+        }
+
+        if let Some(datetime) = get_property(feature, &Property::Datetime).and_then(|v| v.as_str()) {
+            writeln!(writer, "    <time>{}</time>", escape_xml(datetime))?;
+        }
+
+        if let Some(filename) = get_property(feature, &Property::Filename).and_then(|v| v.as_str()) {
+            writeln!(writer, "    <name>{}</name>", escape_xml(filename))?;
+        }
+
+        writeln!(writer, "  </wpt>")?;
+    }
+
+    writeln!(writer, "</gpx>")?;
+
+    Ok(())
+}
+
+pub fn write_kml<W: Write>(features: &[Feature], mut writer: W) -> Result<()> {
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(writer, "  <Document>")?;
+
+    for feature in features {
+        let position = match get_position(feature) {
+            Some(position) => position,
+            None => continue,
+        };
+
+        writeln!(writer, "    <Placemark>")?;
+
+        if let Some(filename) = get_property(feature, &Property::Filename).and_then(|v| v.as_str()) {
+            writeln!(writer, "      <name>{}</name>", escape_xml(filename))?;
+        }
+
+        let description: Vec<&str> = [Property::Path, Property::Datetime].iter()
+            .filter_map(|prop| get_property(feature, prop).and_then(|v| v.as_str()))
+            .collect();
+
+        if !description.is_empty() {
+            writeln!(writer, "      <description>{}</description>", escape_xml(&description.join("\n")))?;
+        }
+
+        let coordinates: Vec<String> = position.iter().map(|c| c.to_string()).collect();
+
+        writeln!(writer, "      <Point>")?;
+        writeln!(writer, "        <coordinates>{}</coordinates>", coordinates.join(","))?;
+        writeln!(writer, "      </Point>")?;
+        writeln!(writer, "    </Placemark>")?;
+    }
+
+    writeln!(writer, "  </Document>")?;
+    writeln!(writer, "</kml>")?;
+
+    Ok(())
+}
+
+/// Writes the features as CSV with the longitude and latitude followed by a
+/// column for each of the given property keys.
+pub fn write_csv<W: Write>(features: &[Feature], keys: &[String], order: CoordinateOrder, mut writer: W) -> Result<()> {
+    let (x, y) = order.apply("longitude", "latitude");
+    let mut header = vec![x.to_string(), y.to_string()];
+    header.extend(keys.iter().map(|key| escape_csv(key)));
+    writeln!(writer, "{}", header.join(","))?;
+
+    for feature in features {
+        let position = match get_position(feature) {
+            Some(position) => position,
+            None => continue,
+        };
+
+        let (x, y) = order.apply(position[0], position[1]);
+        let mut record = vec![x.to_string(), y.to_string()];
+
+        for key in keys {
+            let field = match feature.properties.as_ref().and_then(|props| props.get(key)) {
+                Some(JsonValue::String(s)) => escape_csv(s),
+                Some(JsonValue::Null) | None => String::new(),
+                Some(value) => escape_csv(&value.to_string()),
+            };
+            record.push(field);
+        }
+
+        writeln!(writer, "{}", record.join(","))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A field of a crafted IFD: the tag, type, count and value bytes.
+    type Entry = (u16, u16, u32, Vec<u8>);
+
+    fn ascii(tag: u16, value: &[u8]) -> Entry {
+        (tag, 2, value.len() as u32, value.to_vec())
+    }
+
+    fn rationals(tag: u16, values: &[(u32, u32)]) -> Entry {
+        let data = values.iter().flat_map(|&(num, denom)| [num.to_be_bytes(), denom.to_be_bytes()].concat()).collect();
+        (tag, 5, values.len() as u32, data)
+    }
+
+    fn srationals(tag: u16, values: &[(i32, i32)]) -> Entry {
+        let data = values.iter().flat_map(|&(num, denom)| [num.to_be_bytes(), denom.to_be_bytes()].concat()).collect();
+        (tag, 10, values.len() as u32, data)
+    }
+
+    /// Returns big-endian TIFF data whose IFD0 only points to a GPS IFD with
+    /// the given fields.
+    fn tiff(gps: &[Entry]) -> Vec<u8> {
+        // IFD0 starts after the header and ends at the GPS IFD.
+        let gps_offset = 26;
+        let mut data = b"MM\0\x2a\0\0\0\x08\0\x01\x88\x25\0\x04\0\0\0\x01".to_vec();
+        data.extend_from_slice(&(gps_offset as u32).to_be_bytes());
+        data.extend_from_slice(&[0; 4]);
+
+        let values_offset = gps_offset + 2 + 12 * gps.len() + 4;
+        let mut values = Vec::new();
+
+        data.extend_from_slice(&(gps.len() as u16).to_be_bytes());
+        for &(tag, type_, count, ref value) in gps {
+            data.extend_from_slice(&tag.to_be_bytes());
+            data.extend_from_slice(&type_.to_be_bytes());
+            data.extend_from_slice(&count.to_be_bytes());
+            if value.len() <= 4 {
+                let mut inline = value.clone();
+                inline.resize(4, 0);
+                data.extend_from_slice(&inline);
+            } else {
+                data.extend_from_slice(&((values_offset + values.len()) as u32).to_be_bytes());
+                values.extend_from_slice(value);
+            }
+        }
+        data.extend_from_slice(&[0; 4]);
+        data.extend(values);
+        data
+    }
+
+    /// Returns a JPEG file with the TIFF data in its Exif segment.
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8, 0xff, 0xe1];
+        data.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+        data.extend_from_slice(b"Exif\0\0");
+        data.extend_from_slice(tiff);
+        data.extend_from_slice(&[0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]);
+        data
+    }
+
+    fn gps_reader(gps: &[Entry]) -> exif::Reader {
+        exif::Reader::new(&mut std::io::Cursor::new(tiff(gps))).unwrap()
+    }
+
+    /// Fields of a position at 60°10'N 24°56'E.
+    fn position() -> Vec<Entry> {
+        vec![
+            ascii(1, b"N\0"),
+            rationals(2, &[(60, 1), (10, 1), (0, 1)]),
+            ascii(3, b"E\0"),
+            rationals(4, &[(24, 1), (56, 1), (0, 1)]),
+        ]
+    }
+
+    /// Writes a file to the temporary directory. The name is prefixed with
+    /// the process id so that concurrent test runs don't clash.
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("plag-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(name)
+    }
+
+    fn coordinates(feature: &Feature) -> Vec<f64> {
+        get_position(feature).unwrap().iter().map(|&value| round_to(value, 6)).collect()
+    }
+
+    #[test]
+    fn position_without_altitude_has_two_coordinates() {
+        let path = temp_file("two.jpg", &jpeg(&tiff(&position())));
+        let feature = get_feature(&path, &[]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(coordinates(&feature), [24.933333, 60.166667]);
+    }
+
+    #[test]
+    fn position_with_altitude_has_three_coordinates() {
+        let mut fields = position();
+        fields.push((5, 1, 1, vec![1]));
+        fields.push(rationals(6, &[(25, 2)]));
+
+        let path = temp_file("three.tif", &tiff(&fields));
+        let feature = get_feature(&path, &[]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(coordinates(&feature), [24.933333, 60.166667, -12.5]);
+    }
+
+    #[test]
+    fn position_with_invalid_altitude_has_two_coordinates() {
+        let mut fields = position();
+        fields.push((5, 1, 1, vec![0]));
+        fields.push(rationals(6, &[(0, 0)]));
+
+        let path = temp_file("invalid-altitude.tif", &tiff(&fields));
+        let feature = get_feature(&path, &[Property::Altitude]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(coordinates(&feature), [24.933333, 60.166667]);
+        assert!(get_property(&feature, &Property::Altitude).is_none());
+    }
+
+    #[test]
+    fn degrees_with_zero_over_zero_components() {
+        let reader = gps_reader(&[rationals(2, &[(60, 1), (30, 1), (0, 0)])]);
+        assert_eq!(get_degrees(&reader, exif::Tag::GPSLatitude).unwrap(), 60.5);
+
+        let reader = gps_reader(&[rationals(2, &[(0, 0), (0, 0), (0, 0)])]);
+        assert_eq!(get_degrees(&reader, exif::Tag::GPSLatitude).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn degrees_with_zero_denominator() {
+        let reader = gps_reader(&[rationals(2, &[(60, 1), (10, 0), (0, 1)])]);
+        assert!(matches!(get_degrees(&reader, exif::Tag::GPSLatitude),
+            Err(Error::InvalidField(exif::Tag::GPSLatitude, _))));
+    }
+
+    #[test]
+    fn string_of_zero_length() {
+        let reader = gps_reader(&[ascii(8, b"")]);
+        assert!(matches!(get_string(&reader, exif::Tag::GPSSatellites),
+            Err(Error::InvalidField(exif::Tag::GPSSatellites, _))));
+
+        let reader = gps_reader(&[ascii(8, b"\0")]);
+        assert_eq!(get_string(&reader, exif::Tag::GPSSatellites).unwrap(), "");
+    }
+
+    #[test]
+    fn string_of_several_segments() {
+        let reader = gps_reader(&[ascii(8, b"GPS\0GLONASS\0")]);
+        assert_eq!(get_string(&reader, exif::Tag::GPSSatellites).unwrap(), "GPS GLONASS");
+    }
+
+    #[test]
+    fn coordinate_refs_in_any_case() {
+        for &ref_ in &[&b"s\0"[..], b"south\0", b"SOUTH\0"] {
+            let reader = gps_reader(&[ascii(1, ref_), rationals(2, &[(60, 1), (30, 1), (0, 1)])]);
+            assert_eq!(get_latitude(&reader).unwrap(), -60.5);
+        }
+
+        for &ref_ in &[&b"w\0"[..], b"West\0"] {
+            let reader = gps_reader(&[ascii(3, ref_), rationals(4, &[(24, 1), (30, 1), (0, 1)])]);
+            assert_eq!(get_longitude(&reader).unwrap(), -24.5);
+        }
+
+        let reader = gps_reader(&[ascii(3, b"east\0"), rationals(4, &[(24, 1), (30, 1), (0, 1)])]);
+        assert_eq!(get_longitude(&reader).unwrap(), 24.5);
+    }
+
+    #[test]
+    fn signed_components() {
+        let degrees = srationals(2, &[(-60, 1), (-30, 1), (0, 1)]);
+
+        let reader = gps_reader(std::slice::from_ref(&degrees));
+        assert_eq!(get_degrees(&reader, exif::Tag::GPSLatitude).unwrap(), 60.5);
+
+        // The sign comes from the ref only.
+        let reader = gps_reader(&[ascii(1, b"S\0"), degrees.clone()]);
+        assert_eq!(get_latitude(&reader).unwrap(), -60.5);
+
+        let reader = gps_reader(&[ascii(1, b"N\0"), degrees]);
+        assert_eq!(get_latitude(&reader).unwrap(), 60.5);
+    }
+
+    /// Returns the soft limit of open files of the process. A default is
+    /// used where it can't be read.
+    fn open_files_limit() -> usize {
+        std::fs::read_to_string("/proc/self/limits").ok()
+            .and_then(|limits| limits.lines()
+                .find(|line| line.starts_with("Max open files"))
+                .and_then(|line| line["Max open files".len()..].split_whitespace().next())
+                .and_then(|limit| limit.parse().ok()))
+            .unwrap_or(1024)
+    }
+
+    #[test]
+    fn more_files_than_can_be_open() {
+        let path = temp_file("many.jpg", &jpeg(&tiff(&position())));
+        let paths = vec![path.clone(); open_files_limit().min(65_536) + 1];
+        let (features, errors) = process_files(&paths, &[]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(features.len(), paths.len());
+    }
+
+    #[test]
+    fn dng_with_gps() {
+        let feature = get_feature(&fixture("gps.dng"), &[Property::Make]).unwrap();
+        assert_eq!(coordinates(&feature), [24.933333, 60.166667]);
+        assert_eq!(get_property(&feature, &Property::Make).unwrap(), "Camera");
+    }
+
+    #[test]
+    fn tiff_without_gps() {
+        let error = get_feature(&fixture("nogps.tif"), &[]).unwrap_err();
+        assert!(error.is_missing_gps());
+    }
+
+    #[test]
+    fn motion_photo() {
+        // The fixture has a video appended after the end of the image.
+        let feature = get_feature(&fixture("motion.jpg"), &[]).unwrap();
+        assert_eq!(coordinates(&feature), [24.933333, 60.166667]);
+    }
+
+    #[test]
+    fn xmp_position() {
+        // A JPEG without an Exif segment, with the position in its XMP
+        // segment.
+        let feature = get_feature(&fixture("xmp.jpg"), &[]).unwrap();
+        assert_eq!(coordinates(&feature), [24.933333, 60.166667]);
+
+        // A TIFF with the position in its XMLPacket tag.
+        let feature = get_feature(&fixture("xmp.tif"), &[Property::Make]).unwrap();
+        assert_eq!(coordinates(&feature), [24.933333, 60.166667]);
+        assert_eq!(get_property(&feature, &Property::Make).unwrap(), "Camera");
+    }
+
+    #[test]
+    fn timezones() {
+        assert_eq!("+02:00".parse(), Ok(Timezone::Fixed(120)));
+        assert_eq!("-0530".parse(), Ok(Timezone::Fixed(-330)));
+        assert_eq!("+03".parse(), Ok(Timezone::Fixed(180)));
+        assert_eq!("Z".parse(), Ok(Timezone::Fixed(0)));
+        assert_eq!("Europe/Helsinki".parse(), Ok(Timezone::Named(chrono_tz::Europe::Helsinki)));
+
+        for invalid in &["+1é0", "-é", "+15:00", "+02:60", "+2:00", "+02-00", "+", "Mars/Olympus"] {
+            assert!(invalid.parse::<Timezone>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn precision_above_maximum() {
+        assert_eq!(round_to(24.933333333, 3), 24.933);
+        assert_eq!(round_to(24.933333333, 400), 24.933333333);
+        assert_eq!(round_to(24.933333333, u32::MAX), 24.933333333);
+    }
+
+    const GPX: &str = r#"<?xml version="1.0"?>
+<gpx version="1.1">
+  <trk><trkseg>
+    <trkpt lat="60.2" lon="25.0"><ele>10</ele><time>2020-01-01T12:01:00Z</time></trkpt>
+    <trkpt lat='60.1' lon='24.9'>
+      <time> 2020-01-01T14:00:00+02:00 </time>
+    </trkpt>
+    <trkpt lat="61.0" lon="26.0"/>
+    <trkpt lat="60.3" lon="25.2"><time>2020-01-01T12:02:00Z</time></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+
+    #[test]
+    fn gpx_track_points() {
+        let points = gpx::parse_track_points(GPX).unwrap();
+        // The self-closing point has no time and is ignored, and the points
+        // are sorted by time.
+        let points: Vec<_> = points.iter().map(|point| (point.time, point.latitude, point.longitude)).collect();
+        assert_eq!(points, [(1577880000, 60.1, 24.9), (1577880060, 60.2, 25.0), (1577880120, 60.3, 25.2)]);
+    }
+
+    #[test]
+    fn gpx_errors() {
+        let errors = [
+            ("<trkpt lat=\"60\" lon=\"25\">", "unterminated trkpt element"),
+            ("<trkpt lat=\"60\" lon=\"25\"", "unterminated trkpt element"),
+            ("<trkpt lon=\"25\"/>", "track point without lat"),
+            ("<trkpt lat=\"60\"/>", "track point without lon"),
+            ("<trkpt lat=\"north\" lon=\"25\"/>", "invalid track point lat"),
+            ("<trkpt lat=\"60\" lon=\"25\"><time>2020</trkpt>", "unterminated time element"),
+            ("<trkpt lat=\"60\" lon=\"25\"><time>2020</time></trkpt>", "invalid track point time 2020"),
+        ];
+        for &(gpx, error) in &errors {
+            let result = gpx::parse_track_points(gpx);
+            assert!(result.as_ref().unwrap_err().starts_with(error), "{}: {:?}", gpx, result);
+        }
+
+        assert_eq!("<gpx><trkpt lat=\"60\" lon=\"25\"/></gpx>".parse::<GpxTrack>().unwrap_err(),
+            "no track points with a time");
+    }
+
+    #[test]
+    fn gpx_position_at() {
+        let track: GpxTrack = GPX.parse().unwrap();
+        let round = |position: Option<(f64, f64)>| position.map(|(lat, lon)| (round_to(lat, 6), round_to(lon, 6)));
+
+        // At a track point.
+        assert_eq!(round(track.position_at(1577880060)), Some((60.2, 25.0)));
+        // Between track points.
+        assert_eq!(round(track.position_at(1577880015)), Some((60.125, 24.925)));
+        assert_eq!(round(track.position_at(1577880090)), Some((60.25, 25.1)));
+        // At the ends and outside the track.
+        assert_eq!(round(track.position_at(1577880000)), Some((60.1, 24.9)));
+        assert_eq!(round(track.position_at(1577880120)), Some((60.3, 25.2)));
+        assert_eq!(track.position_at(1577879999), None);
+        assert_eq!(track.position_at(1577880121), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn collect_files_in_directories() {
+        let dir = std::env::temp_dir().join(format!("plag-{}-collect", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.jpg"), b"").unwrap();
+        std::fs::write(dir.join("sub/b.jpg"), b"").unwrap();
+        // A symlink loop is walked only once.
+        std::os::unix::fs::symlink(&dir, dir.join("sub/loop")).unwrap();
+
+        let missing = dir.join("missing.jpg");
+        let (files, errors) = collect_files(&[dir.clone(), missing.clone()], true);
+        assert_eq!(files, [dir.join("a.jpg"), dir.join("sub/b.jpg"), missing.clone()]);
+        assert!(errors.is_empty());
+
+        let (files, errors) = collect_files(std::slice::from_ref(&dir), false);
+        assert_eq!(files, [dir.join("a.jpg")]);
+        assert!(errors.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn html_escapes_markup_in_data() {
+        let feature: Feature = serde_json::from_str(r#"{"type": "Feature",
+            "geometry": {"type": "Point", "coordinates": [24.93, 60.17]},
+            "properties": {"comment": "</script><script>alert(1)</script><!--"}}"#).unwrap();
+
+        let mut page = Vec::new();
+        write_html(vec![feature], &mut page).unwrap();
+        let page = String::from_utf8(page).unwrap();
+
+        assert!(page.contains(r#""comment":"\u003c/script>\u003cscript>alert(1)\u003c/script>\u003c!--""#));
+        assert!(!page.contains("alert(1)</script>"));
+        assert!(page.contains("Leaflet 1.7.1"));
+        assert!(!page.contains("{{"));
+    }
+}
+
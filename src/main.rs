@@ -15,226 +15,1655 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-extern crate geojson;
-extern crate geo_types;
-extern crate exif;
-extern crate serde_json;
+extern crate plag;
 #[macro_use]
 extern crate clap;
+extern crate rayon;
+extern crate glob;
+#[macro_use]
+extern crate serde;
+extern crate toml;
+extern crate geojson;
+extern crate serde_json;
+extern crate flate2;
+
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use flate2::write::GzEncoder;
+use geojson::{Feature, GeoJson};
+use plag::{CoordinateOrder, Crs, DatetimeUnit, Format, IdSource, Property};
+
+arg_enum!{
+    #[derive(PartialEq)]
+    enum ErrorFormat {
+        Human,
+        Json,
+    }
+}
+
+arg_enum!{
+    #[derive(PartialEq)]
+    enum Sort {
+        None,
+        Datetime,
+        Filename,
+    }
+}
+
+/// Number of files processed at a time when output is streamed.
+const STREAM_CHUNK_SIZE: usize = 256;
+
+fn parse_filenames(contents: &str, null: bool) -> Vec<PathBuf> {
+    if null {
+        contents.split('\0').filter(|s| !s.is_empty()).map(PathBuf::from).collect()
+    } else {
+        contents.lines()
+            .map(str::trim)
+            .filter(|s| !s.is_empty() && !s.starts_with('#'))
+            .map(PathBuf::from)
+            .collect()
+    }
+}
+
+fn has_glob_metacharacters(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+/// Returns the path or the file name of the photo of a feature, if either
+/// property is set.
+fn feature_name(feature: &Feature) -> Option<&str> {
+    feature.properties.as_ref()
+        .and_then(|props| props.get("path").or_else(|| props.get("filename")))
+        .and_then(|value| value.as_str())
+}
+
+/// Returns an error as a single-line JSON object for --error-format json.
+fn error_json(path: &Path, error: &plag::Error) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    object.insert("file".to_string(), path.to_string_lossy().into());
+    object.insert("kind".to_string(), error.kind().into());
+    object.insert("message".to_string(), error.to_string().into());
+    object.into()
+}
+
+/// Returns a warning about a photo as a single-line JSON object for
+/// --error-format json.
+fn warning_json(path: &Path, warning: &str) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    object.insert("file".to_string(), path.to_string_lossy().into());
+    object.insert("kind".to_string(), "warning".into());
+    object.insert("message".to_string(), warning.into());
+    object.into()
+}
+
+/// Expands a glob pattern. Patterns that match nothing are reported and
+/// yield no paths.
+fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let paths = match glob::glob(pattern) {
+        Ok(paths) => paths,
+        Err(error) => {
+            eprintln!("warning: {}: invalid pattern: {}", pattern, error);
+            return Vec::new();
+        }
+    };
+
+    let paths: Vec<PathBuf> = paths
+        .filter_map(|path| match path {
+            Ok(path) => Some(path),
+            Err(error) => {
+                eprintln!("warning: {}", error);
+                None
+            }
+        })
+        .collect();
+
+    if paths.is_empty() {
+        eprintln!("warning: {}: pattern did not match any files", pattern);
+    }
+
+    paths
+}
+
+/// Reads the features of a GeoJSON FeatureCollection.
+fn read_features(path: &Path) -> Result<Vec<Feature>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+
+    match contents.parse::<GeoJson>() {
+        Ok(GeoJson::FeatureCollection(collection)) => Ok(collection.features),
+        Ok(_) => Err(format!("{}: not a GeoJSON FeatureCollection", path.display())),
+        Err(error) => Err(format!("{}: invalid GeoJSON: {}", path.display(), error)),
+    }
+}
+
+fn parse_bbox(s: &str) -> Result<[f64; 4], String> {
+    let values: Vec<f64> = s.split(',')
+        .map(|value| value.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|error| format!("invalid bounding box: {}", error))?;
+
+    if values.len() != 4 {
+        return Err("bounding box must have four values: minlon,minlat,maxlon,maxlat".to_string());
+    }
+
+    if values[0] > values[2] || values[1] > values[3] {
+        return Err("bounding box minimum is greater than maximum".to_string());
+    }
+
+    Ok([values[0], values[1], values[2], values[3]])
+}
+
+fn parse_rename(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => Ok((from.to_string(), to.to_string())),
+        _ => Err(format!("invalid rename '{}', expected old=new", s)),
+    }
+}
 
-use std::path::Path;
+/// Members of a FeatureCollection that can't be set with --meta.
+const RESERVED_MEMBERS: &[&str] = &["type", "features", "bbox"];
 
-use geojson::{Feature, GeoJson, Geometry, Value, FeatureCollection};
-use serde_json::{Map, to_value};
+fn parse_meta(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), _) if RESERVED_MEMBERS.contains(&key) => Err(format!("'{}' can't be set with --meta", key)),
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid metadata '{}', expected key=value", s)),
+    }
+}
+
+fn validate_date(s: String) -> Result<(), String> {
+    let bytes = s.as_bytes();
+    let valid = bytes.len() == 10 && bytes.iter().enumerate()
+        .all(|(i, b)| if i == 4 || i == 7 { *b == b'-' } else { b.is_ascii_digit() });
+    if valid {
+        Ok(())
+    } else {
+        Err("expected a date in the form YYYY-MM-DD".to_string())
+    }
+}
 
-#[derive(Debug)]
-enum Error {
-    IoError(std::io::Error),
-    Utf8Error(std::str::Utf8Error),
-    FieldMissing(exif::Tag),
-    InvalidField(exif::Tag, &'static str),
-    ExifError(exif::Error),
+/// Where the output is written, optionally compressed.
+enum Output {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         match self {
-            Error::IoError(error) => write!(f, "{}", error),
-            Error::Utf8Error(error) => write!(f, "{}", error),
-            Error::FieldMissing(tag) => write!(f, "missing field: {}", tag),
-            Error::InvalidField(tag, msg) => write!(f, "invalid field {}: {}", tag, msg),
-            Error::ExifError(error) => write!(f, "{}", error),
+            Output::Plain(writer) => writer.write(buf),
+            Output::Gzip(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(writer) => writer.flush(),
+            Output::Gzip(writer) => writer.flush(),
         }
     }
 }
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+impl Output {
+    fn new(writer: Box<dyn Write>, gzip: bool) -> Output {
+        if gzip {
+            Output::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+        } else {
+            Output::Plain(writer)
+        }
+    }
+
+    /// Writes any buffered data and, when compressing, the gzip trailer.
+    fn finish(self) -> std::io::Result<()> {
         match self {
-            Error::IoError(e) => Some(e),
-            Error::Utf8Error(e) => Some(e),
-            Error::ExifError(e) => Some(e),
-            _ => None,
+            Output::Plain(mut writer) => writer.flush(),
+            Output::Gzip(writer) => writer.finish()?.flush(),
+        }
+    }
+}
+
+/// Returns the path of a numbered output chunk: the number is added before
+/// the extensions of the file name, e.g. `out.geojson` becomes
+/// `out-001.geojson`.
+fn chunk_path(path: &Path, number: usize) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let (stem, extensions) = match name.get(1..).and_then(|rest| rest.find('.')) {
+        Some(pos) => name.split_at(pos + 1),
+        None => (&name[..], ""),
+    };
+    path.with_file_name(format!("{}-{:03}{}", stem, number, extensions))
+}
+
+/// Writes the features in chunks of at most `chunk_size` features to
+/// numbered files named after `path`. An empty chunk is written if there are
+/// no features.
+fn write_chunks<F>(mut features: Vec<Feature>, chunk_size: usize, path: &Path, gzip: bool, write: F) -> plag::Result<()>
+    where F: Fn(Vec<Feature>, &mut Output) -> plag::Result<()>
+{
+    let mut number = 1;
+    loop {
+        let rest = features.split_off(features.len().min(chunk_size));
+        let chunk = std::mem::replace(&mut features, rest);
+
+        let path = chunk_path(path, number);
+        let file = create_output_file(&path).unwrap_or_else(|error| {
+            eprintln!("error: {}: {}", path.display(), error);
+            exit(1);
+        });
+
+        let mut writer = Output::new(Box::new(std::io::BufWriter::new(file)), gzip);
+        write(chunk, &mut writer)?;
+        writer.finish()?;
+        persist_output_file()?;
+
+        if features.is_empty() {
+            return Ok(());
+        }
+        number += 1;
+    }
+}
+
+/// The temporary file output is being written to and the path it is renamed
+/// to once the output is complete.
+static OUTPUT_FILE: Mutex<Option<(PathBuf, PathBuf)>> = Mutex::new(None);
+
+/// Creates a temporary file next to `path` for writing output to, so that an
+/// existing file is not left half-written if writing fails. The file is
+/// renamed to `path` by `persist_output_file`. Targets that exist but are not
+/// regular files, such as devices, FIFOs and symlinks, are written in place.
+fn create_output_file(path: &Path) -> std::io::Result<std::fs::File> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_file() => return std::fs::File::create(path),
+        Ok(metadata) => Some(metadata),
+        Err(_) => None,
+    };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let file = std::fs::File::create(&temp)?;
+    *OUTPUT_FILE.lock().unwrap() = Some((temp, path.to_path_buf()));
+    // A replaced file keeps its permissions.
+    if let Some(metadata) = metadata {
+        file.set_permissions(metadata.permissions())?;
+    }
+    Ok(file)
+}
+
+/// Renames the temporary output file, if any, to its final path.
+fn persist_output_file() -> std::io::Result<()> {
+    match OUTPUT_FILE.lock().unwrap().take() {
+        Some((temp, path)) => std::fs::rename(&temp, &path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp);
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Exits the process, removing the temporary output file if one is being
+/// written.
+fn exit(code: i32) -> ! {
+    if let Ok(mut output) = OUTPUT_FILE.lock() {
+        if let Some((temp, _)) = output.take() {
+            let _ = std::fs::remove_file(temp);
+        }
+    }
+    std::process::exit(code)
+}
+
+/// Name of the config file read from the current directory.
+const CONFIG_FILE: &str = "plag.toml";
+
+/// Defaults read from the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    properties: Option<Vec<String>>,
+    format: Option<String>,
+    precision: Option<u32>,
+    pretty: bool,
+}
+
+/// Reads the config file given with --config, or plag.toml from the current
+/// directory if it exists.
+fn load_config(path: Option<&OsStr>) -> Result<Config, String> {
+    let (path, required) = match path {
+        Some(path) => (Path::new(path), true),
+        None => (Path::new(CONFIG_FILE), false),
+    };
+
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref error) if !required && error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Config::default());
+        }
+        Err(error) => return Err(format!("{}: {}", path.display(), error)),
+    };
+
+    toml::from_str(&contents).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+/// Returns the name of a property with its words separated by hyphens, e.g.
+/// file-mtime.
+fn property_name(property: &str) -> String {
+    let mut name = String::new();
+    for (i, c) in property.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            name.push('-');
         }
+        name.extend(c.to_lowercase());
     }
+    name
 }
 
-impl From<std::str::Utf8Error> for Error {
-    fn from(value: std::str::Utf8Error) -> Error {
-        Error::Utf8Error(value)
+/// Returns the accepted spellings of property names: file-mtime,
+/// file_mtime and filemtime, in any case.
+fn property_values() -> Vec<String> {
+    let mut values = Vec::new();
+    for property in Property::variants().iter() {
+        let name = property_name(property);
+        if name.contains('-') {
+            values.push(name.replace('-', "_"));
+        }
+        values.push(property.to_string());
+        values.push(name);
     }
+    values.push("all".to_string());
+    values
 }
 
-impl From<std::io::Error> for Error {
-    fn from(value: std::io::Error) -> Error {
-        Error::IoError(value)
+fn parse_properties<'a, I: IntoIterator<Item = &'a str>>(values: I) -> Result<Vec<Property>, String> {
+    let mut properties = Vec::new();
+    for value in values {
+        if value.eq_ignore_ascii_case("all") {
+            return Ok(plag::all_properties());
+        }
+        let name: String = value.chars().filter(|&c| c != '-' && c != '_').collect();
+        properties.push(name.parse().map_err(|error| format!("invalid property '{}': {}", value, error))?);
     }
+    Ok(properties)
 }
 
-impl From<exif::Error> for Error {
-    fn from(value: exif::Error) -> Error {
-        Error::ExifError(value)
+/// Settings that can be given both in the config file and on the command
+/// line. Command line flags override the config file.
+struct Settings {
+    properties: Vec<Property>,
+    format: Format,
+    precision: Option<u32>,
+    pretty: bool,
+}
+
+impl Settings {
+    fn new(config: Config, matches: &clap::ArgMatches) -> Result<Settings, String> {
+        let properties = match matches.values_of("properties") {
+            Some(values) => parse_properties(values)?,
+            None => match config.properties {
+                Some(ref values) => parse_properties(values.iter().map(String::as_str))?,
+                None => Vec::new(),
+            },
+        };
+
+        let format = match matches.value_of("format").or(config.format.as_deref()) {
+            Some(format) => format.parse().map_err(|error| format!("invalid format '{}': {}", format, error))?,
+            None => Format::Geojson,
+        };
+
+        let precision = if matches.is_present("precision") {
+            Some(value_t!(matches.value_of("precision"), u32).unwrap_or_else(|e| e.exit()))
+        } else {
+            config.precision
+        };
+        if let Some(precision) = precision.filter(|&precision| precision > plag::MAX_PRECISION) {
+            return Err(format!("invalid precision '{}': must be at most {}", precision, plag::MAX_PRECISION));
+        }
+
+        Ok(Settings {
+            properties,
+            format,
+            precision,
+            pretty: matches.is_present("pretty") || config.pretty,
+        })
     }
 }
 
-type Result<T> = std::result::Result<T, Error>;
+/// Builds the command line interface. The help and the accepted values of
+/// --properties are built at run time.
+fn app<'a, 'b>(properties_help: &'b str, property_values: &'b [&'b str]) -> clap::App<'a, 'b> {
+    clap::App::new("plag")
+    .version("0.1")
+    .author("Oskari Timperi <oskari.timperi@iki.fi>")
+    .about("Photo Location As GeoJSON - Extract GPS location from photos to GeoJSON")
+    .arg(clap::Arg::with_name("pretty")
+        .long("pretty")
+        .help("Output human-readable GeoJSON")
+        .long_help("Output human-readable GeoJSON. Ignored with the ndjson format, which always \
+                    writes one feature per line."))
+    .arg(clap::Arg::with_name("config")
+        .long("config")
+        .takes_value(true)
+        .help("Read default settings from this file instead of plag.toml")
+        .long_help("Read default settings from this file instead of plag.toml in the current \
+                    directory. The file may set properties, format, precision and pretty. \
+                    Command line flags override the file."))
+    .arg(clap::Arg::with_name("indent")
+        .long("indent")
+        .takes_value(true)
+        .value_name("N")
+        .help("Number of spaces to indent with when using --pretty [default: 2]"))
+    .arg(clap::Arg::with_name("output")
+        .long("output")
+        .short("o")
+        .takes_value(true)
+        .help("Write output to a file instead of standard output"))
+    .arg(clap::Arg::with_name("chunk-size")
+        .long("chunk-size")
+        .takes_value(true)
+        .value_name("N")
+        .requires("output")
+        .conflicts_with_all(&["track", "multipoint", "single"])
+        .help("Split the output into numbered files of at most N features")
+        .long_help("Split the GeoJSON output into FeatureCollections of at most N features. \
+                    They are written to files named after --output with a number added, e.g. \
+                    out-001.geojson, out-002.geojson and so on."))
+    .arg(clap::Arg::with_name("append")
+        .long("append")
+        .requires("output")
+        .conflicts_with("chunk-size")
+        .help("Append to the output file instead of overwriting it")
+        .long_help("Append the features to the output file instead of overwriting it. Only \
+                    supported with the ndjson format."))
+    .arg(clap::Arg::with_name("gzip")
+        .long("gzip")
+        .help("Compress the output with gzip"))
+    .arg(clap::Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .help("Output format [default: geojson]")
+        .possible_values(&Format::variants())
+        .case_insensitive(true))
+    .arg(clap::Arg::with_name("coordinate-order")
+        .long("coordinate-order")
+        .takes_value(true)
+        .default_value("lonlat")
+        .possible_values(&CoordinateOrder::variants())
+        .case_insensitive(true)
+        .help("Order of the coordinates in the CSV, WKT and text formats")
+        .long_help("Order of the coordinates in the CSV, WKT and text formats. The default \
+                    for the text format is latlon."))
+    .arg(clap::Arg::with_name("properties")
+        .long("properties")
+        .takes_value(true)
+        .use_delimiter(true)
+        .help("Comma-separated list of properties to set on features.")
+        .long_help(properties_help)
+        .possible_values(property_values)
+        .hide_possible_values(true)
+        .case_insensitive(true))
+    .arg(clap::Arg::with_name("tag")
+        .long("tag")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("TAG")
+        .conflicts_with("point-only")
+        .validator(|s| plag::parse_tag(&s).map(|_| ()))
+        .help("Add the value of an EXIF tag as a property, can be given multiple times")
+        .long_help("Add the value of an EXIF tag as a property named after the tag. The tag \
+                    is given by its name, e.g. DateTimeOriginal, or its number, e.g. 0x9003. \
+                    Can be given multiple times."))
+    .arg(clap::Arg::with_name("rename")
+        .long("rename")
+        .takes_value(true)
+        .use_delimiter(true)
+        .validator(|s| parse_rename(&s).map(|_| ()))
+        .help("Comma-separated list of property keys to rename, e.g. filename=name")
+        .long_help("Comma-separated list of property keys to rename in the output, e.g. \
+                    filename=name,datetime=time. Not used by the GPX and KML formats."))
+    .arg(clap::Arg::with_name("meta")
+        .long("meta")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("KEY=VALUE")
+        .validator(|s| parse_meta(&s).map(|_| ()))
+        .help("Add a member to the GeoJSON FeatureCollection, can be given multiple times"))
+    .arg(clap::Arg::with_name("point-only")
+        .long("point-only")
+        .conflicts_with_all(&["properties", "rename"])
+        .help("Output features without any properties"))
+    .arg(clap::Arg::with_name("precision")
+        .long("precision")
+        .takes_value(true)
+        .help("Round coordinates to this many decimal places"))
+    .arg(clap::Arg::with_name("id")
+        .long("id")
+        .takes_value(true)
+        .possible_values(&IdSource::variants())
+        .case_insensitive(true)
+        .help("Set the id of features from the path, filename or a hash of the file contents"))
+    .arg(clap::Arg::with_name("relative-path")
+        .long("relative-path")
+        .takes_value(true)
+        .value_name("BASE")
+        .help("Make the path property relative to this directory")
+        .long_help("Make the path property relative to this directory. Photos outside of it \
+                    keep their absolute path."))
+    .arg(clap::Arg::with_name("crs")
+        .long("crs")
+        .takes_value(true)
+        .possible_values(&["4326", "3857"])
+        .help("EPSG code of the coordinate reference system to project coordinates to")
+        .long_help("EPSG code of the coordinate reference system to project coordinates to. \
+                    The default is 4326, WGS 84 longitude and latitude. With 3857, Web \
+                    Mercator, the GeoJSON FeatureCollection gets a crs member naming it."))
+    .arg(clap::Arg::with_name("raw-datetime")
+        .long("raw-datetime")
+        .help("Keep the datetime property in the original EXIF format")
+        .long_help("Keep the datetime property in the original EXIF format instead of \
+                    converting it to RFC 3339."))
+    .arg(clap::Arg::with_name("round-datetime")
+        .long("round-datetime")
+        .takes_value(true)
+        .possible_values(&DatetimeUnit::variants())
+        .case_insensitive(true)
+        .conflicts_with("raw-datetime")
+        .help("Truncate the datetime property to the minute, hour or day"))
+    .arg(clap::Arg::with_name("timezone")
+        .long("timezone")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .conflicts_with("raw-datetime")
+        .validator(|s| s.parse::<plag::Timezone>().map(|_| ()))
+        .help("Timezone of datetimes without an offset, e.g. +02:00 or Europe/Helsinki")
+        .long_help("Timezone of datetimes without an offset, given as a UTC offset such as \
+                    +02:00 or an IANA timezone name such as Europe/Helsinki. Offsets stored \
+                    in the photos take precedence. Without this option such datetimes are \
+                    output without an offset."))
+    .arg(clap::Arg::with_name("gpx")
+        .long("gpx")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("Locate photos without a GPS position on a GPX track by their datetime")
+        .long_help("Locate photos without a GPS position on a GPX track by their datetime. \
+                    The position is interpolated between the closest track points. Photos \
+                    taken outside the time range of the track are skipped. Datetimes \
+                    without an offset are taken to be in --timezone, or in UTC if it is not \
+                    given."))
+    .arg(clap::Arg::with_name("bbox")
+        .long("bbox")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .value_name("minlon,minlat,maxlon,maxlat")
+        .validator(|s| parse_bbox(&s).map(|_| ()))
+        .help("Only include photos within the bounding box"))
+    .arg(clap::Arg::with_name("skip-void")
+        .long("skip-void")
+        .help("Skip photos whose GPS status says the measurement is void"))
+    .arg(clap::Arg::with_name("min-accuracy")
+        .long("min-accuracy")
+        .takes_value(true)
+        .value_name("METERS")
+        .help("Skip photos whose GPS horizontal positioning error is larger than METERS")
+        .long_help("Skip photos whose GPS horizontal positioning error is larger than METERS. \
+                    Photos without the error are kept unless --require-accuracy is given."))
+    .arg(clap::Arg::with_name("require-accuracy")
+        .long("require-accuracy")
+        .help("Skip photos without a GPS horizontal positioning error"))
+    .arg(clap::Arg::with_name("since")
+        .long("since")
+        .takes_value(true)
+        .value_name("YYYY-MM-DD")
+        .validator(validate_date)
+        .help("Only include photos taken on or after the date"))
+    .arg(clap::Arg::with_name("until")
+        .long("until")
+        .takes_value(true)
+        .value_name("YYYY-MM-DD")
+        .validator(validate_date)
+        .help("Only include photos taken on or before the date"))
+    .arg(clap::Arg::with_name("sort")
+        .long("sort")
+        .takes_value(true)
+        .default_value("none")
+        .possible_values(&Sort::variants())
+        .case_insensitive(true)
+        .help("Order of the output features"))
+    .arg(clap::Arg::with_name("dedup")
+        .long("dedup")
+        .help("Collapse photos taken at the same location into one feature")
+        .long_help("Collapse photos taken at the same location into one feature. The \
+                    coordinates are compared after rounding them to --precision, if given. \
+                    The number of photos is stored in the count property."))
+    .arg(clap::Arg::with_name("cluster")
+        .long("cluster")
+        .takes_value(true)
+        .value_name("METERS")
+        .help("Group photos taken near each other into one feature")
+        .long_help("Group photos into clusters on a grid of cells of about METERS meters \
+                    and output one feature per cluster, located at the centroid of the \
+                    cluster. The number of photos is stored in the count property. Clusters \
+                    of more than one photo have no other properties."))
+    .arg(clap::Arg::with_name("quiet")
+        .long("quiet")
+        .short("q")
+        .help("Don't print errors for photos that could not be processed"))
+    .arg(clap::Arg::with_name("error-format")
+        .long("error-format")
+        .takes_value(true)
+        .possible_values(&ErrorFormat::variants())
+        .case_insensitive(true)
+        .default_value("human")
+        .help("Format of the errors printed for photos that could not be processed")
+        .long_help("Format of the errors printed for photos that could not be processed. \
+                    With json, each error is printed as a JSON object with the file, kind \
+                    and message members on its own line. Warnings about photos are printed \
+                    the same way with the kind warning."))
+    .arg(clap::Arg::with_name("verbose")
+        .long("verbose")
+        .short("v")
+        .help("Print a summary of the processed files to stderr"))
+    .arg(clap::Arg::with_name("check")
+        .long("check")
+        .conflicts_with("output")
+        .help("Report whether each photo has a usable location instead of writing output")
+        .long_help("Report whether each photo has a usable location instead of writing output. \
+                    Each file is reported as OK, MISSING or ERROR on standard error. Exits \
+                    with a non-zero status if any file is not OK."))
+    .arg(clap::Arg::with_name("progress")
+        .long("progress")
+        .help("Print the number of processed files to stderr while processing"))
+    .arg(clap::Arg::with_name("fail-fast")
+        .long("fail-fast")
+        .help("Stop at the first photo that could not be processed, without writing output"))
+    .arg(clap::Arg::with_name("validate")
+        .long("validate")
+        .help("Report features with coordinates outside the valid ranges")
+        .long_help("Report features whose longitude is outside [-180, 180] or latitude \
+                    outside [-90, 90]. Such coordinates often come from a wrong hemisphere \
+                    reference. The output is not changed. With --strict, exit with a \
+                    non-zero status if any were found."))
+    .arg(clap::Arg::with_name("strict")
+        .long("strict")
+        .help("Do not write any output if a photo could not be processed")
+        .long_help("Do not write any output if a photo could not be processed. Also exit with a \
+                    non-zero status if no features were found."))
+    .arg(clap::Arg::with_name("track")
+        .long("track")
+        .help("Output a single LineString connecting the photos in time order")
+        .long_help("Output a single LineString connecting the photos in time order. Photos \
+                    without a datetime are placed at the end of the line."))
+    .arg(clap::Arg::with_name("multipoint")
+        .long("multipoint")
+        .conflicts_with("track")
+        .help("Output a single MultiPoint of the photo locations without properties"))
+    .arg(clap::Arg::with_name("single")
+        .long("single")
+        .conflicts_with_all(&["track", "multipoint", "merge"])
+        .help("Output the feature of a single photo without a FeatureCollection")
+        .long_help("Output the feature of a single photo as the top-level GeoJSON object \
+                    instead of a FeatureCollection. Exactly one photo must be given."))
+    .arg(clap::Arg::with_name("flatten-collection")
+        .long("flatten-collection")
+        .conflicts_with_all(&["track", "multipoint", "single", "chunk-size"])
+        .help("Output a bare Feature instead of a FeatureCollection when there is one feature")
+        .long_help("Output the feature as the top-level GeoJSON object instead of a \
+                    FeatureCollection when exactly one feature was found. Otherwise the \
+                    FeatureCollection is output as usual."))
+    .arg(clap::Arg::with_name("merge")
+        .long("merge")
+        .takes_value(true)
+        .conflicts_with_all(&["track", "multipoint"])
+        .help("Add the features to those in an existing GeoJSON file")
+        .long_help("Add the features to those in an existing GeoJSON FeatureCollection and \
+                    output them all. Features with the same id as an existing feature are \
+                    skipped. Combine with --id to avoid adding the same photos again."))
+    .arg(clap::Arg::with_name("filelist")
+        .long("filelist")
+        .visible_alias("files-from")
+        .takes_value(true)
+        .help("A file containing filenames")
+        .long_help("A file containing filenames. There must be one filename per line. Blank \
+                    lines and lines starting with # are ignored."))
+    .arg(clap::Arg::with_name("stdin")
+        .long("stdin")
+        .help("Read filenames from standard input")
+        .long_help("Read filenames from standard input. There must be one filename per line. \
+                    Giving - as a filename does the same."))
+    .arg(clap::Arg::with_name("null")
+        .long("null")
+        .short("0")
+        .help("Filenames read from --filelist or standard input are separated by NUL characters"))
+    .arg(clap::Arg::with_name("all-files")
+        .long("all-files")
+        .help("Try to read every file, even ones that don't look like images")
+        .long_help("Try to read every file, even ones that don't look like images. By default \
+                    such files are skipped silently."))
+    .arg(clap::Arg::with_name("glob")
+        .long("glob")
+        .help("Expand wildcards in the given filenames")
+        .long_help("Expand wildcards (*, ? and [...]) in the given filenames. This is useful \
+                    on platforms where the shell doesn't expand them."))
+    .arg(clap::Arg::with_name("exclude")
+        .long("exclude")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("PATTERN")
+        .validator(|s| glob::Pattern::new(&s).map(|_| ()).map_err(|e| e.to_string()))
+        .help("Skip files whose path matches the pattern, can be given multiple times")
+        .long_help("Skip files whose full path matches the pattern, e.g. '*/.thumbnails/*'. \
+                    Can be given multiple times."))
+    .arg(clap::Arg::with_name("max-files")
+        .long("max-files")
+        .takes_value(true)
+        .value_name("N")
+        .help("Stop after extracting features from N photos")
+        .long_help("Stop after extracting features from N photos. Photos that could not be \
+                    processed or were filtered out don't count towards the limit."))
+    .arg(clap::Arg::with_name("no-recursive")
+        .long("no-recursive")
+        .help("Only process the immediate children of directories"))
+    .arg(clap::Arg::with_name("jobs")
+        .long("jobs")
+        .short("j")
+        .takes_value(true)
+        .help("Number of threads used to read photos"))
+    .arg(clap::Arg::with_name("files")
+        .required_unless_one(&["filelist", "stdin"])
+        .multiple(true)
+        .help("A list of photos or directories"))
+}
+
+/// Returns the photos and directories given as arguments, with --filelist
+/// and on standard input.
+fn input_paths(matches: &clap::ArgMatches) -> Vec<PathBuf> {
+    let null = matches.is_present("null");
+
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut read_stdin = matches.is_present("stdin");
 
-fn get_degrees(reader: &exif::Reader, tag: exif::Tag) -> Result<f64> {
-    let field = reader.get_field(tag, false).ok_or(Error::FieldMissing(tag))?;
+    let glob = matches.is_present("glob");
 
-    match field.value {
-        exif::Value::Rational(ref dms) => {
-            if dms.len() != 3 {
-                return Err(Error::InvalidField(tag, "expected 3 rationals"))
+    if let Some(values) = matches.values_of_os("files") {
+        for value in values {
+            if value == "-" {
+                read_stdin = true;
+            } else if glob && value.to_str().is_some_and(has_glob_metacharacters) {
+                paths.extend(expand_glob(value.to_str().unwrap()));
+            } else {
+                paths.push(value.into());
             }
-            let degrees = dms[0].to_f64();
-            let min = dms[1].to_f64();
-            let sec = dms[2].to_f64();
-            Ok(degrees + min/60.0 + sec/3600.0)
-        },
-        _ => Err(Error::InvalidField(tag, "invalid field type"))
+        }
+    }
+
+    if read_stdin {
+        let mut contents = String::new();
+        if let Err(error) = std::io::stdin().read_to_string(&mut contents) {
+            eprintln!("error: stdin: {}", error);
+            exit(1);
+        }
+        paths.extend(parse_filenames(&contents, null));
     }
+
+    if let Some(path) = matches.value_of_os("filelist") {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                eprintln!("error: {}: {}", path.to_string_lossy(), error);
+                exit(1);
+            }
+        };
+        paths.extend(parse_filenames(&contents, null));
+    }
+
+    paths
 }
 
-fn get_string(reader: &exif::Reader, tag: exif::Tag) -> Result<&str> {
-    let field = reader.get_field(tag, false).ok_or(Error::FieldMissing(tag))?;
-    if let exif::Value::Ascii(ref s) = field.value {
-        let s = s[0];
-        std::str::from_utf8(s).map_err(|err| err.into())
+/// Builds the options for extracting features. Exits if they can't be used
+/// together or with the output format.
+fn build_options(matches: &clap::ArgMatches, settings: &Settings) -> plag::Options {
+    let format = settings.format;
+
+    let mut options = plag::Options {
+        raw_datetime: matches.is_present("raw-datetime"),
+        precision: settings.precision,
+        timezone: matches.value_of("timezone").map(|s| s.parse().unwrap()),
+        tags: matches.values_of("tag")
+            .map(|values| values.map(|value| plag::parse_tag(value).unwrap()).collect())
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+
+    // Tags given more than once, e.g. by name and number, are output once.
+    let mut seen_tags = Vec::new();
+    options.tags.retain(|&tag| if seen_tags.contains(&tag) {
+        false
     } else {
-        Err(Error::InvalidField(tag, "field is not a string"))
+        seen_tags.push(tag);
+        true
+    });
+
+    if let Some(path) = matches.value_of_os("gpx") {
+        let track = std::fs::read_to_string(path).map_err(|error| error.to_string())
+            .and_then(|contents| contents.parse::<plag::GpxTrack>());
+        match track {
+            Ok(track) => options.gpx = Some(Arc::new(track)),
+            Err(error) => {
+                eprintln!("error: {}: {}", path.to_string_lossy(), error);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(base) = matches.value_of_os("relative-path") {
+        match Path::new(base).canonicalize() {
+            Ok(base) => options.relative_to = Some(base),
+            Err(error) => {
+                eprintln!("error: {}: {}", base.to_string_lossy(), error);
+                exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("crs") {
+        let code = value_t!(matches.value_of("crs"), u32).unwrap_or_else(|e| e.exit());
+        options.crs = Crs::from_epsg(code).unwrap();
+    }
+
+    if options.crs != Crs::Wgs84 {
+        if matches.is_present("bbox") {
+            eprintln!("error: --bbox can't be used with --crs {}", options.crs.epsg());
+            exit(1);
+        }
+        if matches.is_present("cluster") {
+            eprintln!("error: --cluster can't be used with --crs {}", options.crs.epsg());
+            exit(1);
+        }
+        if matches.is_present("validate") {
+            eprintln!("error: --validate can't be used with --crs {}", options.crs.epsg());
+            exit(1);
+        }
+        if format == Format::Gpx || format == Format::Kml || format == Format::Html {
+            eprintln!("error: the {} format requires WGS 84 coordinates", format.to_string().to_lowercase());
+            exit(1);
+        }
+    }
+
+    if matches.is_present("id") {
+        options.id = Some(value_t!(matches.value_of("id"), IdSource).unwrap_or_else(|e| e.exit()));
+    }
+
+    if matches.is_present("round-datetime") {
+        options.round_datetime = Some(value_t!(matches.value_of("round-datetime"), DatetimeUnit).unwrap_or_else(|e| e.exit()));
     }
+
+    options
 }
 
-fn get_latitude(reader: &exif::Reader) -> Result<f64> {
-    let mut latitude = get_degrees(reader, exif::Tag::GPSLatitude)?;
-    let ref_ = get_string(reader, exif::Tag::GPSLatitudeRef)?;
-    if ref_.ends_with("S") {
-        latitude = -latitude;
+/// Returns the --rename pairs. Keys that are not set by any property or tag
+/// are warned about.
+fn parse_renames(matches: &clap::ArgMatches, options: &plag::Options) -> Vec<(String, String)> {
+    let renames: Vec<(String, String)> = matches.values_of("rename")
+        .map(|values| values.map(|value| parse_rename(value).unwrap()).collect())
+        .unwrap_or_default();
+
+    let known_keys: Vec<String> = plag::all_properties().iter().flat_map(Property::keys)
+        .chain(Property::Thumbnail.keys())
+        .chain(Property::Sha256.keys())
+        .chain(Some("count".to_string()))
+        .chain(options.tags.iter().map(|&tag| plag::tag_key(tag)))
+        .collect();
+
+    for (from, _) in &renames {
+        if !known_keys.contains(from) {
+            eprintln!("warning: --rename: unknown property key '{}'", from);
+        }
     }
-    Ok(latitude)
+
+    renames
 }
 
-fn get_longitude(reader: &exif::Reader) -> Result<f64> {
-    let mut longitude = get_degrees(reader, exif::Tag::GPSLongitude)?;
-    let ref_ = get_string(reader, exif::Tag::GPSLongitudeRef)?;
-    if ref_.ends_with("W") {
-        longitude = -longitude;
+/// Counts of the processed files.
+#[derive(Default)]
+struct Stats {
+    failures: usize,
+    missing_gps: usize,
+    out_of_range: usize,
+    extracted: usize,
+}
+
+impl Stats {
+    /// Prints the counts for --verbose.
+    fn print(&self, files: usize) {
+        eprintln!("files: {}", files);
+        eprintln!("features: {}", self.extracted);
+        eprintln!("missing gps: {}", self.missing_gps);
+        eprintln!("other errors: {}", self.failures - self.missing_gps);
     }
-    Ok(longitude)
 }
 
-arg_enum!{
-    enum Property {
-        Filename,
-        Path,
-        Datetime,
+/// Filters applied to the extracted features.
+struct Filter<'a> {
+    bbox: Option<[f64; 4]>,
+    since: Option<&'a str>,
+    until: Option<&'a str>,
+    skip_void: bool,
+    min_accuracy: Option<f64>,
+    require_accuracy: bool,
+    validate: bool,
+    quiet: bool,
+    /// Properties that were read only for the filters.
+    strip: Vec<Property>,
+}
+
+impl<'a> Filter<'a> {
+    /// Reads the filters from the command line and adds the properties they
+    /// need to `read_properties`. The datetime is kept if `keep_datetime` is
+    /// true, as it is needed after filtering.
+    fn new(matches: &'a clap::ArgMatches, valid_properties: &[Property], read_properties: &mut Vec<Property>,
+        keep_datetime: bool) -> Filter<'a>
+    {
+        let since = matches.value_of("since");
+        let until = matches.value_of("until");
+
+        if (keep_datetime || since.is_some() || until.is_some()) && !read_properties.contains(&Property::Datetime) {
+            read_properties.push(Property::Datetime);
+        }
+
+        let skip_void = matches.is_present("skip-void");
+
+        if skip_void && !read_properties.contains(&Property::GpsStatus) {
+            read_properties.push(Property::GpsStatus);
+        }
+
+        let min_accuracy = if matches.is_present("min-accuracy") {
+            Some(value_t!(matches.value_of("min-accuracy"), f64).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        };
+        let require_accuracy = matches.is_present("require-accuracy");
+        let filter_accuracy = min_accuracy.is_some() || require_accuracy;
+
+        if filter_accuracy && !read_properties.contains(&Property::Accuracy) {
+            read_properties.push(Property::Accuracy);
+        }
+
+        // The datetime is stripped only after sorting when it is kept.
+        let strip = [
+            (Property::Datetime, !keep_datetime),
+            (Property::GpsStatus, skip_void),
+            (Property::Accuracy, filter_accuracy),
+        ];
+        let strip = strip.iter()
+            .filter(|&&(property, read)| read && read_properties.contains(&property) && !valid_properties.contains(&property))
+            .map(|&(property, _)| property)
+            .collect();
+
+        Filter {
+            bbox: matches.value_of("bbox").map(|s| parse_bbox(s).unwrap()),
+            since,
+            until,
+            skip_void,
+            min_accuracy,
+            require_accuracy,
+            validate: matches.is_present("validate"),
+            quiet: matches.is_present("quiet"),
+            strip,
+        }
+    }
+
+    /// Returns the feature if it passes the filters.
+    fn apply(&self, mut feature: Feature, stats: &mut Stats) -> Option<Feature> {
+        if self.bbox.is_some_and(|bbox| !plag::is_within(&feature, &bbox)) {
+            return None;
+        }
+
+        if self.since.is_some() || self.until.is_some() {
+            let date = match plag::get_date(&feature) {
+                Some(date) => date,
+                None => {
+                    if !self.quiet {
+                        match feature_name(&feature) {
+                            Some(name) => eprintln!("warning: {}: skipping a photo without a valid datetime", name),
+                            None => eprintln!("warning: skipping a photo without a valid datetime"),
+                        }
+                    }
+                    return None;
+                }
+            };
+            if self.since.is_some_and(|since| date.as_str() < since)
+                || self.until.is_some_and(|until| date.as_str() > until)
+            {
+                return None;
+            }
+        }
+
+        if self.skip_void && plag::is_void(&feature) {
+            return None;
+        }
+
+        if self.min_accuracy.is_some() || self.require_accuracy {
+            match plag::get_accuracy(&feature) {
+                Some(accuracy) if self.min_accuracy.is_some_and(|min_accuracy| accuracy > min_accuracy) => return None,
+                None if self.require_accuracy => return None,
+                _ => {},
+            }
+        }
+
+        if self.validate {
+            if let Some((lon, lat)) = plag::out_of_range_position(&feature) {
+                match feature_name(&feature) {
+                    Some(name) => eprintln!("warning: {}: coordinates out of range: {}, {}", name, lon, lat),
+                    None => eprintln!("warning: coordinates out of range: {}, {}", lon, lat),
+                }
+                stats.out_of_range += 1;
+            }
+        }
+
+        for property in &self.strip {
+            plag::remove_property(&mut feature, property);
+        }
+
+        stats.extracted += 1;
+
+        Some(feature)
     }
 }
 
-fn get_feature(filename: &Path, properties: &[Property]) -> Result<Feature> {
-    let file = std::fs::File::open(filename)?;
+/// Extracts features from files, reports the files that could not be
+/// processed and applies the filters.
+struct Extractor<'a> {
+    properties: Vec<Property>,
+    options: plag::Options,
+    filter: Filter<'a>,
+    all_files: bool,
+    error_format: ErrorFormat,
+    quiet: bool,
+    fail_fast: bool,
+    stats: Stats,
+}
+
+impl Extractor<'_> {
+    /// Returns the files that may be photos.
+    fn candidates(&self, paths: &[PathBuf]) -> Vec<PathBuf> {
+        paths.iter().filter(|path| self.all_files || plag::is_image_candidate(path)).cloned().collect()
+    }
+
+    /// Returns the result of every file that may be a photo, in order. The
+    /// results are not reported or filtered.
+    fn results(&self, paths: &[PathBuf]) -> Vec<(PathBuf, plag::Result<Feature>)> {
+        let paths = self.candidates(paths);
+        let results = plag::process_each_file(&paths, &self.properties, &self.options);
+        paths.into_iter().zip(results).collect()
+    }
 
-    let reader = exif::Reader::new(&mut std::io::BufReader::new(&file))?;
+    /// Returns the features of the files that pass the filters.
+    fn extract(&mut self, paths: &[PathBuf]) -> Vec<Feature> {
+        let paths = self.candidates(paths);
+        let (features, errors) = plag::process_files_with_options(&paths, &self.properties, &self.options);
 
-    let latitude = get_latitude(&reader)?;
-    let longitude = get_longitude(&reader)?;
-    let point: geo_types::Point<f64> = (longitude, latitude).into();
+        for (path, error) in &errors {
+            self.report(path, error);
+        }
+
+        features.into_iter().filter_map(|feature| self.filter.apply(feature, &mut self.stats)).collect()
+    }
+
+    /// Reports a file that could not be processed. Exits with --fail-fast.
+    fn report(&mut self, path: &Path, error: &plag::Error) {
+        if self.error_format == ErrorFormat::Json {
+            if self.fail_fast || !self.quiet {
+                eprintln!("{}", error_json(path, error));
+            }
+        } else if self.fail_fast {
+            eprintln!("error: {}: {}", path.display(), error);
+        } else if !self.quiet {
+            eprintln!("{}: {}", path.display(), error);
+        }
+        if self.fail_fast {
+            exit(1);
+        }
+        if error.is_missing_gps() {
+            self.stats.missing_gps += 1;
+        }
+        self.stats.failures += 1;
+    }
+}
 
-    let mut props = Map::new();
+/// Reports whether each file has a usable location for --check. Returns
+/// true if all of them do.
+fn check(extractor: &Extractor, files: &[PathBuf]) -> bool {
+    let mut ok = true;
 
-    for prop in properties {
-        let key = prop.to_string();
-        let value = match prop {
-            Property::Filename => to_value(filename.file_name().unwrap().to_string_lossy()),
-            Property::Path => {
-                let path = filename.canonicalize()?;
-                to_value(path.to_string_lossy())
+    for (path, result) in extractor.results(files) {
+        match result {
+            Ok(_) => eprintln!("{}: OK", path.display()),
+            Err(ref error) if error.is_missing_gps() => {
+                eprintln!("{}: MISSING", path.display());
+                ok = false;
             },
-            Property::Datetime => {
-                let data = get_string(&reader, exif::Tag::DateTimeOriginal)?;
-                to_value(data)
+            Err(error) => {
+                eprintln!("{}: ERROR: {}", path.display(), error);
+                ok = false;
+            }
+        }
+    }
+
+    ok
+}
+
+/// Prints the number of processed files with --progress.
+struct Progress {
+    enabled: bool,
+    processed: usize,
+    total: usize,
+}
+
+impl Progress {
+    fn advance(&mut self, count: usize) {
+        self.processed += count;
+        if self.enabled {
+            eprintln!("processed {}/{}", self.processed, self.total);
+        }
+    }
+}
+
+/// How the features are written.
+struct WriteOptions<'a> {
+    format: Format,
+    indent: Option<usize>,
+    order: CoordinateOrder,
+    track: bool,
+    multipoint: bool,
+    single: bool,
+    flatten: bool,
+    meta: Option<serde_json::Map<String, serde_json::Value>>,
+    chunk_size: Option<usize>,
+    output: Option<&'a OsStr>,
+    append: bool,
+    gzip: bool,
+    point_only: bool,
+    renames: Vec<(String, String)>,
+}
+
+impl<'a> WriteOptions<'a> {
+    /// Reads the output options from the command line. Exits if they can't
+    /// be used with the format.
+    fn new(matches: &'a clap::ArgMatches, settings: &Settings, options: &plag::Options) -> WriteOptions<'a> {
+        let format = settings.format;
+
+        let indent = if matches.is_present("indent") {
+            let indent = value_t!(matches.value_of("indent"), usize).unwrap_or_else(|e| e.exit());
+            if !settings.pretty {
+                eprintln!("warning: --indent is ignored without --pretty");
+            }
+            indent
+        } else {
+            2
+        };
+
+        let point_only = matches.is_present("point-only");
+        let renames = parse_renames(matches, options);
+
+        let order = if format == Format::Text && matches.occurrences_of("coordinate-order") == 0 {
+            CoordinateOrder::Latlon
+        } else {
+            value_t!(matches.value_of("coordinate-order"), CoordinateOrder).unwrap_or_else(|e| e.exit())
+        };
+
+        if matches.occurrences_of("coordinate-order") > 0 && format != Format::Csv && format != Format::Wkt
+            && format != Format::Text
+        {
+            eprintln!("warning: --coordinate-order is ignored with the {} format", format.to_string().to_lowercase());
+        }
+
+        let track = matches.is_present("track");
+        let multipoint = matches.is_present("multipoint");
+        let single = matches.is_present("single");
+        let flatten = matches.is_present("flatten-collection");
+
+        let geojson_only = [
+            (track, "--track"),
+            (multipoint, "--multipoint"),
+            (single, "--single"),
+            (flatten, "--flatten-collection"),
+            (matches.is_present("merge"), "--merge"),
+        ];
+        for &(present, flag) in &geojson_only {
+            if present && format != Format::Geojson {
+                eprintln!("error: {} is only supported with GeoJSON output", flag);
+                exit(1);
+            }
+        }
+
+        if matches.is_present("meta") && (format != Format::Geojson || track || multipoint || single) {
+            eprintln!("warning: --meta is only used with GeoJSON FeatureCollection output");
+        }
+
+        let mut meta: Option<serde_json::Map<_, _>> = matches.values_of("meta").map(|values| {
+            values.map(|value| parse_meta(value).unwrap())
+                .map(|(key, value)| (key, value.into()))
+                .collect()
+        });
+        if options.crs != Crs::Wgs84 {
+            meta.get_or_insert_with(Default::default).insert("crs".to_string(), options.crs.to_json());
+        }
+
+        let chunk_size = if matches.is_present("chunk-size") {
+            let chunk_size = value_t!(matches.value_of("chunk-size"), usize).unwrap_or_else(|e| e.exit());
+            if chunk_size == 0 {
+                eprintln!("error: --chunk-size must be at least 1");
+                exit(1);
             }
+            if format != Format::Geojson {
+                eprintln!("error: --chunk-size is only supported with GeoJSON output");
+                exit(1);
+            }
+            Some(chunk_size)
+        } else {
+            None
+        };
+
+        let append = matches.is_present("append");
+
+        if append && format != Format::Ndjson {
+            eprintln!("error: --append is only supported with ndjson output");
+            exit(1);
+        }
+
+        WriteOptions {
+            format,
+            indent: if settings.pretty { Some(indent) } else { None },
+            order,
+            track,
+            multipoint,
+            single,
+            flatten,
+            meta,
+            chunk_size,
+            output: matches.value_of_os("output"),
+            append,
+            gzip: matches.is_present("gzip"),
+            point_only,
+            renames,
+        }
+    }
+
+    /// Opens the output file, or standard output if there is none. Exits if
+    /// the file can't be created.
+    fn open(&self) -> Output {
+        let output: Box<dyn Write> = match self.output {
+            // The chunks are written to files of their own.
+            Some(_) if self.chunk_size.is_some() => Box::new(std::io::sink()),
+            Some(path) => {
+                // Appended output is written in place as the existing
+                // contents are kept anyway.
+                let file = if self.append {
+                    std::fs::OpenOptions::new().create(true).append(true).open(path)
+                } else {
+                    create_output_file(Path::new(path))
+                };
+                match file {
+                    Ok(file) => Box::new(std::io::BufWriter::new(file)),
+                    Err(error) => {
+                        eprintln!("error: {}: {}", path.to_string_lossy(), error);
+                        exit(1);
+                    }
+                }
+            },
+            None => Box::new(std::io::stdout().lock()),
         };
-        props.insert(key, value.unwrap());
+
+        Output::new(output, self.gzip)
     }
 
-    Ok(Feature {
-        bbox: None,
-        geometry: Some(Geometry::new(Value::from(&point))),
-        id: None,
-        properties: Some(props),
-        foreign_members: None,
+    /// Prepares the properties of a feature for output.
+    fn finish(&self, feature: &mut Feature) {
+        if self.point_only {
+            feature.properties = None;
+        } else {
+            plag::rename_properties(feature, &self.renames);
+        }
+    }
+
+    /// Writes all features at once. `csv_keys` are the columns of the CSV
+    /// format.
+    fn write(&self, mut features: Vec<Feature>, csv_keys: &[String], writer: &mut Output) -> plag::Result<()> {
+        let indent = self.indent;
+        match self.format {
+            Format::Geojson if self.track => plag::write_track(features, indent, writer),
+            Format::Geojson if self.multipoint => plag::write_multipoint(&features, indent, writer),
+            Format::Geojson if self.single => match features.pop() {
+                Some(feature) => plag::write_feature(feature, indent, writer),
+                None => Ok(()),
+            },
+            Format::Geojson if self.flatten && features.len() == 1 => {
+                plag::write_feature(features.pop().unwrap(), indent, writer)
+            },
+            Format::Geojson => match self.chunk_size {
+                Some(chunk_size) => {
+                    let output = Path::new(self.output.unwrap());
+                    write_chunks(features, chunk_size, output, self.gzip, |features, writer| {
+                        plag::write_geojson(features, self.meta.clone(), indent, writer)
+                    })
+                },
+                None => plag::write_geojson(features, self.meta.clone(), indent, writer),
+            },
+            Format::Gpx => plag::write_gpx(&features, writer),
+            Format::Kml => plag::write_kml(&features, writer),
+            Format::Csv => plag::write_csv(&features, csv_keys, self.order, writer),
+            Format::Ndjson => plag::write_ndjson(&features, writer),
+            Format::Wkt => plag::write_wkt(&features, self.order, writer),
+            Format::Text => plag::write_text(&features, self.order, writer),
+            Format::Html => plag::write_html(features, writer),
+            Format::Topojson => plag::write_topojson(&features, writer),
+            Format::Summary => unreachable!(),
+        }
+    }
+}
+
+/// Returns the columns of the CSV format: the keys of the properties and
+/// tags, and the count of collapsed photos if there is one, after renaming.
+fn csv_keys(properties: &[Property], options: &plag::Options, counted: bool, renames: &[(String, String)]) -> Vec<String> {
+    properties.iter()
+        .flat_map(Property::keys)
+        .chain(options.tags.iter().map(|&tag| plag::tag_key(tag)))
+        .chain(if counted { Some("count".to_string()) } else { None })
+        .map(|key| match renames.iter().find(|(from, _)| *from == key) {
+            Some((_, to)) => to.clone(),
+            None => key,
+        })
+        .collect()
+}
+
+/// Writes a summary line for every file, including the ones that failed,
+/// as soon as a chunk of files has been processed. Stops once `remaining`
+/// features have been extracted.
+fn write_summary(extractor: &mut Extractor, files: &[PathBuf], mut remaining: usize, progress: &mut Progress,
+    writer: &mut Output) -> plag::Result<()>
+{
+    files.chunks(STREAM_CHUNK_SIZE).try_for_each(|chunk| {
+        if remaining == 0 {
+            return Ok(());
+        }
+
+        extractor.results(chunk).into_iter().try_for_each(|(path, result)| {
+            if remaining == 0 {
+                return Ok(());
+            }
+
+            let result = match result {
+                Ok(feature) => match extractor.filter.apply(feature, &mut extractor.stats) {
+                    Some(feature) => {
+                        remaining -= 1;
+                        Ok(feature)
+                    },
+                    None => return Ok(()),
+                },
+                Err(error) => {
+                    extractor.report(&path, &error);
+                    Err(error)
+                }
+            };
+            plag::write_summary(&path, &result, &mut *writer)
+        })?;
+
+        progress.advance(chunk.len());
+        Ok(())
     })
 }
 
+/// Writes the features as line-delimited GeoJSON as soon as a chunk of files
+/// has been processed, so that memory use stays bounded. Chunks are
+/// processed in order, so the output order still follows the input order.
+fn stream_ndjson(extractor: &mut Extractor, write_options: &WriteOptions, files: &[PathBuf], mut remaining: usize,
+    progress: &mut Progress, writer: &mut Output) -> plag::Result<()>
+{
+    files.chunks(STREAM_CHUNK_SIZE).try_for_each(|chunk| {
+        if remaining == 0 {
+            return Ok(());
+        }
+
+        let mut features = extractor.extract(chunk);
+        features.truncate(remaining);
+        remaining -= features.len();
+        for feature in &mut features {
+            write_options.finish(feature);
+        }
+        progress.advance(chunk.len());
+        plag::write_ndjson(&features, &mut *writer)
+    })
+}
+
+/// Extracts the features of at most `remaining` photos.
+fn extract_all(extractor: &mut Extractor, files: &[PathBuf], remaining: usize, progress: &mut Progress) -> Vec<Feature> {
+    let mut features = Vec::new();
+    for chunk in files.chunks(STREAM_CHUNK_SIZE) {
+        if features.len() >= remaining {
+            break;
+        }
+        features.extend(extractor.extract(chunk));
+        progress.advance(chunk.len());
+    }
+    features.truncate(remaining);
+    features
+}
+
 fn main() {
-    let matches = clap::App::new("plag")
-        .version("0.1")
-        .author("Oskari Timperi <oskari.timperi@iki.fi>")
-        .about("Photo Location As GeoJSON - Extract GPS location from photos to GeoJSON")
-        .arg(clap::Arg::with_name("pretty")
-            .long("pretty")
-            .help("Output human-readable GeoJSON"))
-        .arg(clap::Arg::with_name("properties")
-            .long("properties")
-            .takes_value(true)
-            .use_delimiter(true)
-            .help("Comma-separated list of properties to set on features.")
-            .possible_values(&Property::variants())
-            .case_insensitive(true))
-        .arg(clap::Arg::with_name("filelist")
-            .long("filelist")
-            .takes_value(true)
-            .help("A file containing filenames")
-            .long_help("A file containing filenames. There must be one filename per line."))
-        .arg(clap::Arg::with_name("files")
-            .required_unless("filelist")
-            .multiple(true)
-            .help("A list of photos"))
-        .get_matches();
-
-    let mut all_files: Vec<std::ffi::OsString> = Vec::new();
+    let property_values = property_values();
+    let property_values: Vec<&str> = property_values.iter().map(String::as_str).collect();
+    let property_names: Vec<String> = Property::variants().iter().map(|property| property_name(property)).collect();
+    let properties_help = format!("Comma-separated list of properties to set on features. Use all to set \
+                                   every available property except thumbnail and sha256. Words in \
+                                   property names may be separated by - or _, or not at all.\n\
+                                   [possible values: {}, all]", property_names.join(", "));
 
-    if let Some(values) = matches.values_of_os("files") {
-        all_files.extend(values.map(|s| s.into()));
+    let matches = app(&properties_help, &property_values).get_matches();
+
+    let paths = input_paths(&matches);
+
+    let settings = load_config(matches.value_of_os("config"))
+        .and_then(|config| Settings::new(config, &matches))
+        .unwrap_or_else(|error| {
+            eprintln!("error: {}", error);
+            exit(1);
+        });
+
+    let mut options = build_options(&matches, &settings);
+    let write_options = WriteOptions::new(&matches, &settings, &options);
+    let format = settings.format;
+
+    // Properties from the config file are ignored with --point-only.
+    let valid_properties = if write_options.point_only { Vec::new() } else { settings.properties };
+
+    let existing = matches.value_of_os("merge").map(|path| {
+        read_features(Path::new(path)).unwrap_or_else(|error| {
+            eprintln!("error: {}", error);
+            exit(1);
+        })
+    });
+
+    let sort = value_t!(matches.value_of("sort"), Sort).unwrap_or_else(|e| e.exit());
+
+    // The track and sorting by date need the datetime after filtering, so it
+    // has to be read even when it was not requested.
+    let mut read_properties = valid_properties.clone();
+    let filter = Filter::new(&matches, &valid_properties, &mut read_properties,
+        write_options.track || sort == Sort::Datetime);
+
+    if matches.is_present("jobs") {
+        let jobs = value_t!(matches.value_of("jobs"), usize).unwrap_or_else(|e| e.exit());
+        rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global().unwrap();
     }
 
-    if let Some(path) = matches.value_of_os("filelist") {
-        let contents = std::fs::read_to_string(path);
-        if contents.is_err() {
-            eprintln!("error: {}: {}", path.to_string_lossy(), contents.unwrap_err());
-            std::process::exit(1);
+    let error_format = value_t!(matches.value_of("error-format"), ErrorFormat).unwrap_or_else(|e| e.exit());
+
+    let (mut files, errors) = plag::collect_files(&paths, !matches.is_present("no-recursive"));
+    for (path, error) in &errors {
+        if error_format == ErrorFormat::Json {
+            eprintln!("{}", error_json(path, error));
+        } else {
+            eprintln!("{}: {}", path.display(), error);
         }
-        let contents = contents.unwrap();
-        all_files.extend(contents.lines().map(str::trim).filter(|s| !s.is_empty()).map(|s| s.into()));
     }
 
-    let valid_properties = values_t!(matches.values_of("properties"), Property).unwrap_or_else(|e| e.exit());
+    if let Some(patterns) = matches.values_of("exclude") {
+        let patterns: Vec<glob::Pattern> = patterns.map(|pattern| glob::Pattern::new(pattern).unwrap()).collect();
+        files.retain(|path| !patterns.iter().any(|pattern| pattern.matches_path(path)));
+    }
+
+    // The output follows the order of the files, so sorting them by name is
+    // enough to sort the features.
+    if sort == Sort::Filename {
+        files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    }
 
-    let features: Vec<_> = all_files.into_iter()
-        .filter_map(|path| {
-            match get_feature(Path::new(&path), &valid_properties) {
-                Ok(feature) => Some(feature),
-                Err(error) => {
-                    eprintln!("{}: {}", path.to_string_lossy(), error);
-                    None
+    if write_options.single && files.len() != 1 {
+        eprintln!("error: --single requires exactly one photo, got {}", files.len());
+        exit(1);
+    }
+
+    let quiet = matches.is_present("quiet");
+    let verbose = matches.is_present("verbose");
+
+    if !quiet {
+        let json = error_format == ErrorFormat::Json;
+        options.warning_handler = Some(plag::WarningHandler::new(move |path, warning| if json {
+            eprintln!("{}", warning_json(path, warning));
+        } else {
+            eprintln!("{}: warning: {}", path.display(), warning);
+        }));
+    }
+
+    let fail_fast = matches.is_present("fail-fast");
+
+    let mut extractor = Extractor {
+        properties: read_properties,
+        options,
+        filter,
+        all_files: matches.is_present("all-files"),
+        error_format,
+        quiet,
+        fail_fast,
+        stats: Stats::default(),
+    };
+
+    if matches.is_present("check") {
+        exit(if check(&extractor, &files) { 0 } else { 1 });
+    }
+
+    let strict = matches.is_present("strict");
+    let dedup = matches.is_present("dedup");
+    let cluster = if matches.is_present("cluster") {
+        let radius = value_t!(matches.value_of("cluster"), f64).unwrap_or_else(|e| e.exit());
+        if radius <= 0.0 || !radius.is_finite() {
+            eprintln!("error: --cluster must be a positive distance");
+            exit(1);
+        }
+        Some(radius)
+    } else {
+        None
+    };
+
+    // Number of features to extract at most. Files are processed in chunks
+    // so that processing can stop once the limit is reached.
+    let remaining = if matches.is_present("max-files") {
+        value_t!(matches.value_of("max-files"), usize).unwrap_or_else(|e| e.exit())
+    } else {
+        usize::MAX
+    };
+
+    // All arguments must be validated by now, as exiting through clap would
+    // leave the temporary output file behind.
+    let mut writer = write_options.open();
+
+    let mut progress = Progress {
+        enabled: matches.is_present("progress"),
+        processed: 0,
+        total: files.len(),
+    };
+
+    let result = if format == Format::Summary {
+        write_summary(&mut extractor, &files, remaining, &mut progress, &mut writer)
+    } else if format == Format::Ndjson && !strict && !fail_fast && !dedup && cluster.is_none() && sort != Sort::Datetime {
+        stream_ndjson(&mut extractor, &write_options, &files, remaining, &mut progress, &mut writer)
+    } else {
+        let mut features = extract_all(&mut extractor, &files, remaining, &mut progress);
+
+        if extractor.stats.failures > 0 && strict {
+            if verbose {
+                extractor.stats.print(files.len());
+            }
+            eprintln!("error: {} file(s) could not be processed", extractor.stats.failures);
+            exit(1);
+        }
+
+        if sort == Sort::Datetime {
+            plag::sort_by_datetime(&mut features);
+
+            if !valid_properties.contains(&Property::Datetime) {
+                for feature in &mut features {
+                    plag::remove_property(feature, &Property::Datetime);
                 }
             }
-        })
-        .collect();
+        }
+
+        if dedup {
+            features = plag::dedup_features(features, extractor.options.precision);
+        }
+
+        if let Some(radius) = cluster {
+            features = plag::cluster_features(features, radius, extractor.options.precision);
+        }
 
-    let collection = FeatureCollection {
-        bbox: None,
-        features: features,
-        foreign_members: None,
+        // This is done last as the steps above look up properties by their
+        // original keys.
+        for feature in &mut features {
+            write_options.finish(feature);
+        }
+
+        if let Some(existing) = existing {
+            features = plag::merge_features(existing, features);
+        }
+
+        let csv_keys = csv_keys(&valid_properties, &extractor.options, dedup || cluster.is_some(),
+            &write_options.renames);
+        write_options.write(features, &csv_keys, &mut writer)
     };
 
-    let geojson = GeoJson::from(collection);
+    let result = result.and_then(|_| {
+        writer.finish()?;
+        persist_output_file()?;
+        Ok(())
+    });
 
-    if matches.is_present("pretty") {
-        serde_json::to_writer_pretty(std::io::stdout(), &geojson).unwrap();
-    } else {
-        serde_json::to_writer(std::io::stdout(), &geojson).unwrap();
+    let stats = &extractor.stats;
+
+    if verbose {
+        stats.print(files.len());
+    }
+
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        exit(1);
+    }
+
+    if stats.extracted == 0 {
+        eprintln!("note: no features found in {} file(s)", files.len());
+        if strict {
+            exit(1);
+        }
+    }
+
+    if stats.out_of_range > 0 && strict {
+        eprintln!("error: {} feature(s) have coordinates out of range", stats.out_of_range);
+        exit(1);
+    }
+
+    if stats.failures > 0 {
+        exit(1);
     }
 }
@@ -0,0 +1,77 @@
+/*
+    Copyright (C) 2018 Oskari Timperi <oskari.timperi@iki.fi>
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Minimal reading of XMP packets embedded in image files.
+//!
+//! Only simple properties are supported, written either as attributes
+//! (`exif:GPSLatitude="..."`) or as elements
+//! (`<exif:GPSLatitude>...</exif:GPSLatitude>`).
+
+const START: &[u8] = b"<x:xmpmeta";
+const END: &[u8] = b"</x:xmpmeta>";
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Returns the first XMP packet found in the data.
+pub fn find_packet(data: &[u8]) -> Option<&str> {
+    let start = find(data, START)?;
+    let end = start + find(&data[start..], END)? + END.len();
+    std::str::from_utf8(&data[start..end]).ok()
+}
+
+/// Returns the value of a simple property, e.g. `exif:GPSLatitude`.
+pub fn get_property<'a>(packet: &'a str, name: &str) -> Option<&'a str> {
+    let attribute = format!("{}=", name);
+    if let Some(pos) = packet.find(&attribute) {
+        let rest = &packet[pos + attribute.len()..];
+        let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let rest = &rest[1..];
+        return rest.find(quote).map(|end| &rest[..end]);
+    }
+
+    let element = format!("<{}>", name);
+    let pos = packet.find(&element)?;
+    let rest = &packet[pos + element.len()..];
+    rest.find('<').map(|end| rest[..end].trim())
+}
+
+/// Parses a GPS coordinate in the XMP format `DDD,MM,SSk` or `DDD,MM.mmk`,
+/// where `k` is one of N, S, E or W.
+pub fn parse_coordinate(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let direction = value.chars().last()?;
+    let negative = match direction.to_ascii_uppercase() {
+        'N' | 'E' => false,
+        'S' | 'W' => true,
+        _ => return None,
+    };
+
+    let components = value[..value.len() - direction.len_utf8()]
+        .split(',')
+        .map(|component| component.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+
+    let degrees = match components[..] {
+        [degrees, min] => degrees + min / 60.0,
+        [degrees, min, sec] => degrees + min / 60.0 + sec / 3600.0,
+        _ => return None,
+    };
+
+    Some(if negative { -degrees } else { degrees })
+}